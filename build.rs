@@ -1,7 +1,7 @@
-use std::{error::Error, path::PathBuf, env};
+use std::error::Error;
 
 fn main() -> Result<(), Box<dyn Error>> {
-    let proto_file = "./proto/qc.proto";
+    let proto_files = ["./proto/qc.proto", "./proto/health.proto"];
     // let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
 
     tonic_build::configure()
@@ -9,7 +9,7 @@ fn main() -> Result<(), Box<dyn Error>> {
         .build_server(true)
         // .file_descriptor_set_path(out_dir.join("qc_description.bin"))
         .out_dir("./src/lib")
-        .compile(&[proto_file], &["proto"])?;
+        .compile(&proto_files, &["proto"])?;
 
     Ok(())
 }
\ No newline at end of file
@@ -0,0 +1,6 @@
+# record check: the first value is not above the second, e.g. dew point and temperature
+def run(level, datetime, data):
+    first, second = list(data.values())[:2]
+    return {
+        "res": first <= second,
+    }
@@ -0,0 +1,5 @@
+# derived parameter: dew point spread, temperature minus dew point
+def derive(datetime, data):
+    return {
+        "value": data["temperature"] - data["dewpoint"],
+    }
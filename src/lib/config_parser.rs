@@ -1,9 +1,15 @@
-use std::fmt::Debug;
+use std::{fmt::Debug, hash::Hasher, str::FromStr};
 
 use serde_derive::{Deserialize, Serialize};
 use toml::Table;
 
-use super::QCModule;
+use super::{
+    expr,
+    maintenance::MaintenanceWindow,
+    qc_worker::{StateConfig, WARN_LEVELS},
+    storage::{archive::ArchiveConfig, StorageConfig},
+    QCModule, ERROR,
+};
 
 #[macro_export]
 macro_rules! get_config {
@@ -16,22 +22,59 @@ macro_rules! get_config {
     };
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 struct Meatadata {
     max_level: u64,
+    version: Option<String>,
+    training: u64,
+    ordering: OrderPolicy,
+    reorder_window: u64,
+    interval: Option<u64>,
+    gap_tolerance: Option<u64>,
 }
 
-#[derive(Debug, Default)]
+/// `Global.ordering`, what is done with a value older than the last checked one.
+/// A value with the timestamp of a checked or buffered one is a duplicate and always dropped.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OrderPolicy {
+    /// check it anyway, checks which keep a history may misbehave
+    Accept,
+    /// drop it
+    Reject,
+    /// store it flagged `Late`, without running the checks
+    #[default]
+    Flag,
+    /// hold values `Global.reorder_window` seconds and check them in time order,
+    /// values later than that are flagged `Late`
+    Buffer,
+}
+
+impl FromStr for OrderPolicy {
+    type Err = ERROR;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "accept" => Ok(OrderPolicy::Accept),
+            "reject" => Ok(OrderPolicy::Reject),
+            "flag" => Ok(OrderPolicy::Flag),
+            "buffer" => Ok(OrderPolicy::Buffer),
+            _ => Err(format!("Unknown ordering: {s}, expected accept, reject, flag or buffer").into()),
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone)]
 pub struct LevelPattern {
     pub module: Option<Vec<ExtModule>>,
     pub errorflag: Option<bool>,
 }
 
-#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub enum ModuleType {
     Unknown = 0,
     General = 1,
     Python = 2,
+    Expr = 3,
 }
 
 impl<T: AsRef<str>> From<T> for ModuleType {
@@ -41,6 +84,7 @@ impl<T: AsRef<str>> From<T> for ModuleType {
             "c" => ModuleType::General,
             "rust" => ModuleType::General,
             "python" => ModuleType::Python,
+            "expr" => ModuleType::Expr,
             _ => ModuleType::Unknown,
         }
     }
@@ -51,34 +95,99 @@ pub struct ExtModule {
     pub name: String,
     pub module_type: ModuleType,
     pub path: String,
+    /// `module_type = "expr"`, true when the value passes
+    pub expr: Option<String>,
     pub instance: Option<Box<dyn QCModule + 'static>>,
+    /// the error bit is set too when this module fails
     pub errorflag: bool,
 }
 
-impl Debug for (dyn QCModule + 'static) {
+/// A copy has no module loaded, every worker loads and keeps its own.
+impl Clone for ExtModule {
+    fn clone(&self) -> Self {
+        ExtModule {
+            name: self.name.clone(),
+            module_type: self.module_type,
+            path: self.path.clone(),
+            expr: self.expr.clone(),
+            instance: None,
+            errorflag: self.errorflag,
+        }
+    }
+}
+
+impl Debug for dyn QCModule + 'static {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.write_str("QCModule")
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct QCConfig {
     metadata: Meatadata,
     levels: Vec<LevelPattern>,
+    hash: String,
 }
 
 impl QCConfig {
-    pub fn new(path: &str) -> Self {
-        let data;
+    /// Returns an error instead of panicking on a broken config,
+    /// a running daemon loads configs when new parameters arrive and on reload.
+    pub fn load(path: &str) -> Result<Self, ERROR> {
         // println!("{path:?}");
-
-        get_config!(data, path, Table);
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| format!("Can't open config file {path}: {e}"))?;
+        let data = toml::from_str::<Table>(&contents)?;
 
         // println!("{data:?}");
 
         let metadata = Meatadata {
-            max_level: data["Global"]["max_level"].as_integer().unwrap() as u64,
+            max_level: data
+                .get("Global")
+                .and_then(|v| v.get("max_level"))
+                .and_then(|v| v.as_integer())
+                .ok_or_else(|| format!("Missing Global.max_level in {path}"))?
+                as u64,
+            version: data
+                .get("Global")
+                .and_then(|v| v.get("version"))
+                .map(|v| match v {
+                    toml::Value::String(v) => v.to_string(),
+                    v => v.to_string(),
+                }),
+            training: data
+                .get("Global")
+                .and_then(|v| v.get("training"))
+                .and_then(|v| v.as_integer())
+                .unwrap_or_default()
+                .max(0) as u64,
+            ordering: data
+                .get("Global")
+                .and_then(|v| v.get("ordering"))
+                .and_then(|v| v.as_str())
+                .map(|v| v.parse().map_err(|e| format!("Global.ordering of {path}: {e}")))
+                .transpose()?
+                .unwrap_or_default(),
+            reorder_window: data
+                .get("Global")
+                .and_then(|v| v.get("reorder_window"))
+                .and_then(|v| v.as_integer())
+                .unwrap_or_default()
+                .max(0) as u64,
+            interval: data
+                .get("Global")
+                .and_then(|v| v.get("interval"))
+                .and_then(|v| v.as_integer())
+                .filter(|v| *v > 0)
+                .map(|v| v as u64),
+            gap_tolerance: data
+                .get("Global")
+                .and_then(|v| v.get("gap_tolerance"))
+                .and_then(|v| v.as_integer())
+                .map(|v| v.max(0) as u64),
         };
+        if metadata.max_level as usize >= WARN_LEVELS {
+            return Err(format!("Global.max_level of {path} must be below {WARN_LEVELS}").into());
+        }
         let mut levels = Vec::new();
 
         for i in 0..=metadata.max_level {
@@ -86,18 +195,29 @@ impl QCConfig {
 
             if !data.contains_key(&section) {
                 // TODO: Record error
+                // keep the index of the following levels
+                levels.push(LevelPattern::default());
                 continue;
             }
 
             if let Some(table) = data[&section].as_table() {
-                levels.push(QCConfig::parse_level(table));
+                levels.push(QCConfig::parse_level(table)?);
             }
         }
 
-        Self { metadata, levels }
+        // stable between builds, unlike DefaultHasher
+        let mut hasher = fnv::FnvHasher::default();
+        hasher.write(contents.as_bytes());
+        let hash = format!("{:016x}", hasher.finish());
+
+        Ok(Self {
+            metadata,
+            levels,
+            hash,
+        })
     }
 
-    fn parse_level(data: &Table) -> LevelPattern {
+    fn parse_level(data: &Table) -> Result<LevelPattern, ERROR> {
         let mut res = LevelPattern::default();
 
         if data.contains_key("module") {
@@ -105,10 +225,27 @@ impl QCConfig {
                 let mut module_list = Vec::new();
                 for value in values {
                     if let Some(val) = value.as_table() {
+                        let get_str = |key: &str| -> Result<String, ERROR> {
+                            Ok(val
+                                .get(key)
+                                .and_then(|v| v.as_str())
+                                .ok_or_else(|| format!("Missing module {key}"))?
+                                .to_string())
+                        };
+                        let module_type = ModuleType::from(get_str("module_type")?);
+                        let (path, expr) = if module_type == ModuleType::Expr {
+                            let expr = get_str("expr")?;
+                            // a broken expression fails the config, not every value
+                            expr::compile(&expr)?;
+                            (String::new(), Some(expr))
+                        } else {
+                            (get_str("path")?, None)
+                        };
                         module_list.push(ExtModule {
-                            name: val["name"].as_str().unwrap().to_string(),
-                            module_type: ModuleType::from(val["module_type"].as_str().unwrap()),
-                            path: val["path"].as_str().unwrap().to_string(),
+                            name: get_str("name")?,
+                            module_type,
+                            path,
+                            expr,
                             instance: None,
                             errorflag: if let Some(toml::Value::Boolean(v)) = val.get("errorflag") {
                                 *v
@@ -122,7 +259,45 @@ impl QCConfig {
             }
         }
 
-        res
+        Ok(res)
+    }
+
+    /// `Global.version`, if the config has one.
+    pub fn version(&self) -> Option<&str> {
+        self.metadata.version.as_deref()
+    }
+
+    /// Hash of the config file, tells which config produced a stored row.
+    pub fn hash(&self) -> &str {
+        &self.hash
+    }
+
+    /// Values the checks see before their results count, `Global.training`.
+    pub fn training(&self) -> usize {
+        self.metadata.training as usize
+    }
+
+    /// `Global.ordering`, `Flag` when not given.
+    pub fn ordering(&self) -> OrderPolicy {
+        self.metadata.ordering
+    }
+
+    /// Seconds values are held back with `OrderPolicy::Buffer`, `Global.reorder_window`.
+    pub fn reorder_window(&self) -> i64 {
+        self.metadata.reorder_window as i64
+    }
+
+    /// Expected time between values, `Global.interval` seconds; no gap detection without it.
+    pub fn interval(&self) -> Option<chrono::Duration> {
+        self.metadata.interval.map(|v| chrono::Duration::seconds(v as i64))
+    }
+
+    /// Time without values until they are missing, `Global.interval` plus `Global.gap_tolerance`
+    /// seconds, half the interval when no tolerance is given.
+    pub fn gap_after(&self) -> Option<chrono::Duration> {
+        let interval = self.metadata.interval?;
+        let tolerance = self.metadata.gap_tolerance.unwrap_or(interval / 2);
+        Some(chrono::Duration::seconds((interval + tolerance) as i64))
     }
 
     pub fn max_level(&self) -> usize {
@@ -138,13 +313,57 @@ impl QCConfig {
     }
 }
 
+/// Settings of the daemon itself, `config/daemon.toml` by default.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct DaemonConfig {
+    #[serde(default)]
+    pub storage: StorageConfig,
+    /// keep received lines, off when missing
+    pub archive: Option<ArchiveConfig>,
+    /// `[[maintenance]]`, periods flagged `Maintenance`
+    #[serde(default)]
+    pub maintenance: Vec<MaintenanceWindow>,
+    /// saving and restoring the worker state
+    #[serde(default)]
+    pub state: StateConfig,
+}
+
+impl DaemonConfig {
+    /// Defaults when the file does not exist.
+    pub fn load(path: &str) -> Result<Self, ERROR> {
+        if !std::path::Path::new(path).exists() {
+            return Ok(DaemonConfig::default());
+        }
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| format!("Can't open config file {path}: {e}"))?;
+        Ok(toml::from_str(&contents).map_err(|e| format!("Invalid config {path}: {e}"))?)
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use super::QCConfig;
+    use crate::lib::storage::Backend;
+
+    use super::{DaemonConfig, QCConfig};
 
     #[test]
     fn testcase1() {
-        let qc = QCConfig::new("./config/temperature.toml");
+        let qc = QCConfig::load("./config/temperature.toml").unwrap();
         println!("{qc:?}");
     }
+
+    #[test]
+    fn testcase2() {
+        let config = DaemonConfig::load("./config/daemon.toml").unwrap();
+        assert_eq!(config.storage.backend, Backend::Sqlite);
+
+        let config = DaemonConfig::load("./config/missing.toml").unwrap();
+        assert_eq!(config.storage.root, "database");
+
+        let config: DaemonConfig =
+            toml::from_str("[storage]\nbackend = \"parquet\"\nrows_per_file = 10").unwrap();
+        assert_eq!(config.storage.backend, Backend::Parquet);
+        assert_eq!(config.storage.rows_per_file, 10);
+        assert!(toml::from_str::<DaemonConfig>("[storage]\nbackend = \"mysql\"").is_err());
+    }
 }
@@ -0,0 +1,838 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use tokio::sync::{broadcast, mpsc, watch};
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{Request, Response, Status, Streaming};
+
+use super::{
+    data_parser::{parse_datetime, with_protocol},
+    health::{
+        health_check_response::ServingStatus, health_server::Health, HealthCheckRequest,
+        HealthCheckResponse,
+    },
+    qc::{
+        admin_server::Admin, qc_server::Qc, stream_response, BatchRequest, BatchResponse, LineResult, QcValue,
+        QueryRequest, QueryRow, SendRequest, SendResponse, StreamResponse, StreamSummary,
+        ListParametersRequest, ListParametersResponse, ModuleState, ParameterInfo,
+        ParameterListResponse, ParameterRequest, SubscribeRequest, WorkerState,
+        WorkerStateResponse, ListMaintenanceRequest, MaintenanceList, MaintenanceWindow,
+        OverrideRequest, OverrideResponse, RemoveMaintenanceRequest,
+    },
+    config_parser::DaemonConfig,
+    qc_worker::{configured_parameters, QCFlag, QCworker},
+    storage::{FlagOverride, QueryFilter, Record, StorageConfig},
+    ERROR,
+};
+
+// number of responses buffered per stream before we stop reading the client
+const STREAM_BUFFER: usize = 64;
+// number of results kept for subscribers that fall behind
+const EVENT_BUFFER: usize = 1024;
+
+/// Every QC result processed by the daemon is published here for `Subscribe`.
+pub type Events = broadcast::Sender<QueryRow>;
+
+/// Worker shared by every request of the daemon, so QC state is kept between requests.
+pub type SharedWorker = Arc<Mutex<QCworker>>;
+
+#[derive(Clone)]
+pub struct QcDaemon {
+    worker: SharedWorker,
+    events: Events,
+    // set on shutdown, ends the long living streams
+    closing: Arc<watch::Sender<bool>>,
+    storage: StorageConfig,
+}
+
+impl QcDaemon {
+    /// Results are written to the store of `config`.
+    pub fn new(config: &DaemonConfig) -> Result<Self, ERROR> {
+        let mut qc = QCworker::new(HashMap::new());
+        qc.set_store(config.storage.open()?);
+        if let Some(archive) = &config.archive {
+            qc.set_archive(archive.open()?);
+        }
+        qc.set_config_maintenance(config.maintenance.clone());
+        let (events, _) = broadcast::channel(EVENT_BUFFER);
+        let (closing, _) = watch::channel(false);
+        Ok(QcDaemon {
+            worker: Arc::new(Mutex::new(qc)),
+            events,
+            closing: Arc::new(closing),
+            storage: config.storage.clone(),
+        })
+    }
+
+    pub fn worker(&self) -> SharedWorker {
+        self.worker.clone()
+    }
+
+    /// Save and publish gap records of the parameters which stopped reporting,
+    /// see `QCworker::check_gaps`.
+    pub fn check_gaps(&self) -> Result<(), ERROR> {
+        let mut qc = self.worker.lock().unwrap();
+        let records = qc.check_gaps(chrono::offset::Local::now().naive_local());
+        if records.is_empty() {
+            return Ok(());
+        }
+        qc.save()?;
+        for record in records {
+            let _ = self.events.send(to_row(record));
+        }
+        Ok(())
+    }
+
+    /// End `Stream` (with its summary) and `Subscribe` calls, so the server can drain.
+    pub fn close(&self) {
+        self.closing.send_replace(true);
+    }
+}
+
+/// Services reported by the health service, "" is the whole server.
+const HEALTH_SERVICES: [&str; 3] = ["", "qc.QC", "qc.Admin"];
+
+/// `grpc.health.v1.Health`, every service is serving until `set_serving(false)`.
+#[derive(Clone)]
+pub struct HealthDaemon {
+    status: Arc<watch::Sender<ServingStatus>>,
+}
+
+impl Default for HealthDaemon {
+    fn default() -> Self {
+        let (status, _) = watch::channel(ServingStatus::Serving);
+        HealthDaemon {
+            status: Arc::new(status),
+        }
+    }
+}
+
+impl HealthDaemon {
+    pub fn set_serving(&self, serving: bool) {
+        self.status.send_replace(if serving {
+            ServingStatus::Serving
+        } else {
+            ServingStatus::NotServing
+        });
+    }
+}
+
+/// Resolves on SIGINT (ctrl-c) or SIGTERM.
+pub async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("Failed to listen for ctrl-c");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("Failed to listen for SIGTERM")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}
+
+pub struct AdminDaemon {
+    worker: SharedWorker,
+}
+
+impl AdminDaemon {
+    pub fn new(worker: SharedWorker) -> Self {
+        AdminDaemon { worker }
+    }
+}
+
+pub fn to_values(report: &[Record]) -> Vec<QcValue> {
+    report
+        .iter()
+        .map(|record| QcValue {
+            parameter: record.parameter.to_string(),
+            datetime: record.datetime.format("%Y-%m-%dT%H:%M:%S").to_string(),
+            value: record.value.to_string(),
+            flag: record.flag.bits(),
+        })
+        .collect()
+}
+
+fn to_row(record: Record) -> QueryRow {
+    QueryRow {
+        datetime: record.datetime.format("%Y-%m-%dT%H:%M:%S").to_string(),
+        parameter: record.parameter,
+        station: record.station.unwrap_or_default(),
+        value: record.value.to_string(),
+        flag: record.flag.bits(),
+        revision: record.revision,
+    }
+}
+
+/// Send the values to the subscribers, if any.
+pub fn publish(events: &Events, station: Option<&str>, values: &[QcValue]) {
+    if events.receiver_count() == 0 {
+        return;
+    }
+    for value in values {
+        let _ = events.send(QueryRow {
+            datetime: value.datetime.clone(),
+            parameter: value.parameter.clone(),
+            station: station.unwrap_or_default().to_string(),
+            value: value.value.clone(),
+            flag: value.flag,
+            revision: 0,
+        });
+    }
+}
+
+/// Peer of a request for the raw archive, unix socket peers have no address.
+fn source<T>(request: &Request<T>) -> Option<String> {
+    Some(
+        request
+            .remote_addr()
+            .map_or_else(|| "unix".to_string(), |addr| addr.to_string()),
+    )
+}
+
+/// Run QC on one line with the given worker, save and publish the result.
+pub fn process_line(qc: &mut QCworker, index: u64, raw_data: &str, events: &Events) -> LineResult {
+    let result = qc.handler(raw_data).and_then(|report| {
+        qc.save()?;
+        Ok(report)
+    });
+
+    match result {
+        Ok(report) => {
+            let values = to_values(&report);
+            publish(events, qc.station(), &values);
+            LineResult {
+                index,
+                status: "Ok".to_string(),
+                values,
+            }
+        }
+        Err(e) => LineResult {
+            index,
+            status: e.to_string(),
+            values: Vec::new(),
+        },
+    }
+}
+
+/// Count one processed line into the summary.
+pub fn update_summary(summary: &mut StreamSummary, line: &LineResult) {
+    summary.received += 1;
+    if line.status == "Ok" {
+        summary.succeeded += 1;
+    } else {
+        summary.failed += 1;
+    }
+    if line.values.iter().any(|v| v.flag != 0) {
+        summary.flagged += 1;
+    }
+}
+
+#[tonic::async_trait]
+impl Qc for QcDaemon {
+    async fn send(&self, request: Request<SendRequest>) -> Result<Response<SendResponse>, Status> {
+        let raw_data = with_protocol(request.get_ref().protocol, &request.get_ref().payload);
+        let peer = source(&request);
+        let station = request.into_inner().station;
+        let events = self.events.clone();
+        let worker = self.worker();
+
+        tokio::task::spawn_blocking(move || {
+            let mut qc = worker.lock().unwrap();
+            qc.set_station(station);
+            qc.set_source(peer);
+            // boxed, a Status is large
+            let report = qc
+                .handler(&raw_data)
+                .map_err(|e| Box::new(Status::invalid_argument(e.to_string())))?;
+            qc.save().map_err(|e| Box::new(Status::internal(e.to_string())))?;
+            publish(&events, qc.station(), &to_values(&report));
+            Ok(())
+        })
+        .await
+        .map_err(|e| Status::internal(e.to_string()))?
+        .map_err(|e: Box<Status>| *e)?;
+
+        Ok(Response::new(SendResponse {
+            status: "Ok".to_string(),
+        }))
+    }
+
+    type StreamStream = ReceiverStream<Result<StreamResponse, Status>>;
+
+    async fn stream(
+        &self,
+        request: Request<Streaming<SendRequest>>,
+    ) -> Result<Response<Self::StreamStream>, Status> {
+        let peer = source(&request);
+        let mut inbound = request.into_inner();
+        let (tx, rx) = mpsc::channel(STREAM_BUFFER);
+        let events = self.events.clone();
+        let worker = self.worker();
+        let mut closing = self.closing.subscribe();
+
+        tokio::spawn(async move {
+            let mut summary = StreamSummary::default();
+
+            loop {
+                let message = tokio::select! {
+                    _ = closing.wait_for(|v| *v) => break,
+                    message = inbound.message() => message,
+                };
+                let request = match message {
+                    Ok(Some(request)) => request,
+                    Ok(None) => break,
+                    Err(status) => {
+                        let _ = tx.send(Err(status)).await;
+                        return;
+                    }
+                };
+
+                let raw_data = with_protocol(request.protocol, &request.payload);
+                let (worker, peer, events, index) =
+                    (worker.clone(), peer.clone(), events.clone(), summary.received);
+                let line = tokio::task::spawn_blocking(move || {
+                    let mut qc = worker.lock().unwrap();
+                    qc.set_station(request.station);
+                    qc.set_source(peer);
+                    process_line(&mut qc, index, &raw_data, &events)
+                })
+                .await;
+                let line = match line {
+                    Ok(line) => line,
+                    Err(e) => {
+                        let _ = tx.send(Err(Status::internal(e.to_string()))).await;
+                        return;
+                    }
+                };
+                update_summary(&mut summary, &line);
+
+                // waits while the client is not reading, which stops us pulling more lines
+                let response = StreamResponse {
+                    result: Some(stream_response::Result::Line(line)),
+                };
+                if tx.send(Ok(response)).await.is_err() {
+                    // client gone
+                    return;
+                }
+            }
+
+            let _ = tx
+                .send(Ok(StreamResponse {
+                    result: Some(stream_response::Result::Summary(summary)),
+                }))
+                .await;
+        });
+
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+
+    async fn send_batch(
+        &self,
+        request: Request<BatchRequest>,
+    ) -> Result<Response<BatchResponse>, Status> {
+        let peer = source(&request);
+        let request = request.into_inner();
+        let events = self.events.clone();
+        let worker = self.worker();
+
+        let response = tokio::task::spawn_blocking(move || {
+            let mut qc = worker.lock().unwrap();
+            qc.set_station(request.station);
+            qc.set_source(peer);
+
+            let mut response = BatchResponse {
+                results: Vec::with_capacity(request.payloads.len()),
+                summary: Some(StreamSummary::default()),
+            };
+            for (index, payload) in request.payloads.iter().enumerate() {
+                let raw_data = with_protocol(request.protocol, payload);
+                let line = process_line(&mut qc, index as u64, &raw_data, &events);
+                update_summary(response.summary.as_mut().unwrap(), &line);
+                response.results.push(line);
+            }
+            response
+        })
+        .await
+        .map_err(|e| Status::internal(e.to_string()))?;
+
+        Ok(Response::new(response))
+    }
+
+    type QueryStream = ReceiverStream<Result<QueryRow, Status>>;
+
+    async fn query(
+        &self,
+        request: Request<QueryRequest>,
+    ) -> Result<Response<Self::QueryStream>, Status> {
+        let request = request.into_inner();
+        let filter = QueryFilter {
+            parameters: request.parameters,
+            start: parse_datetime(&request.start)
+                .map_err(|e| Status::invalid_argument(e.to_string()))?,
+            end: parse_datetime(&request.end)
+                .map_err(|e| Status::invalid_argument(e.to_string()))?,
+            station: request.station,
+            flag_mask: request.flag_mask,
+        };
+
+        let (tx, rx) = mpsc::channel(STREAM_BUFFER);
+        let storage = self.storage.clone();
+        tokio::task::spawn_blocking(move || {
+            let send = &mut |record: Record| {
+                // stop reading the database once the client is gone
+                tx.blocking_send(Ok(to_row(record))).is_ok()
+            };
+            // a new store, so the query does not wait for the worker
+            let result = storage.open().and_then(|store| store.query(&filter, send));
+            if let Err(e) = result {
+                let _ = tx.blocking_send(Err(Status::internal(e.to_string())));
+            }
+        });
+
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+
+    type SubscribeStream = ReceiverStream<Result<QueryRow, Status>>;
+
+    async fn subscribe(
+        &self,
+        request: Request<SubscribeRequest>,
+    ) -> Result<Response<Self::SubscribeStream>, Status> {
+        let request = request.into_inner();
+        let mut events = self.events.subscribe();
+        let mut closing = self.closing.subscribe();
+        let (tx, rx) = mpsc::channel(STREAM_BUFFER);
+
+        tokio::spawn(async move {
+            loop {
+                let row = tokio::select! {
+                    _ = tx.closed() => return,
+                    _ = closing.wait_for(|v| *v) => return,
+                    row = events.recv() => row,
+                };
+                let row = match row {
+                    Ok(row) => row,
+                    Err(broadcast::error::RecvError::Lagged(n)) => {
+                        // the client would miss results without knowing, it has to subscribe again
+                        let status = Status::data_loss(format!("Subscriber lagged, {n} results skipped"));
+                        let _ = tx.send(Err(status)).await;
+                        return;
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return,
+                };
+
+                if !request.parameters.is_empty() && !request.parameters.contains(&row.parameter) {
+                    continue;
+                }
+                if let Some(station) = &request.station {
+                    if *station != row.station {
+                        continue;
+                    }
+                }
+                if let Some(mask) = request.flag_mask {
+                    if row.flag & mask == 0 {
+                        continue;
+                    }
+                }
+                if tx.send(Ok(row)).await.is_err() {
+                    return;
+                }
+            }
+        });
+
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+}
+
+fn to_worker_state(state: crate::lib::qc_worker::WorkerState) -> WorkerState {
+    WorkerState {
+        parameter: state.parameter.to_string(),
+        station: state.station,
+        status: state.status,
+        last: state.data.map(|(datetime, data)| QcValue {
+            parameter: state.parameter,
+            datetime: datetime.format("%Y-%m-%dT%H:%M:%S").to_string(),
+            value: data.to_string(),
+            flag: state.flag.bits(),
+        }),
+        modules: state
+            .modules
+            .into_iter()
+            .map(|module| ModuleState {
+                level: module.level as u32,
+                name: module.name,
+                module_type: module.module_type,
+                loaded: module.loaded,
+            })
+            .collect(),
+        rejected: state.rejected,
+        duplicates: state.duplicates,
+        buffered: state.buffered as u64,
+    }
+}
+
+fn to_maintenance_list(windows: &[crate::lib::maintenance::MaintenanceWindow]) -> MaintenanceList {
+    MaintenanceList {
+        windows: windows
+            .iter()
+            .map(|window| MaintenanceWindow {
+                start: window.start.format("%Y-%m-%dT%H:%M:%S").to_string(),
+                end: window.end.format("%Y-%m-%dT%H:%M:%S").to_string(),
+                station: window.station.clone(),
+                parameters: window.parameters.clone(),
+                reason: window.reason.to_string(),
+            })
+            .collect(),
+    }
+}
+
+#[tonic::async_trait]
+impl Admin for AdminDaemon {
+    async fn reload_config(
+        &self,
+        request: Request<ParameterRequest>,
+    ) -> Result<Response<ParameterListResponse>, Status> {
+        let target = request.into_inner().parameter;
+        let worker = self.worker.clone();
+        let parameters = tokio::task::spawn_blocking(move || {
+            worker.lock().unwrap().reload_config(target.as_deref()).map_err(|e| e.to_string())
+        })
+        .await
+        .map_err(|e| Status::internal(e.to_string()))?
+        .map_err(Status::failed_precondition)?;
+        Ok(Response::new(ParameterListResponse { parameters }))
+    }
+
+    async fn list_parameters(
+        &self,
+        _request: Request<ListParametersRequest>,
+    ) -> Result<Response<ListParametersResponse>, Status> {
+        let worker = self.worker.clone();
+        let (configured, running) = tokio::task::spawn_blocking(move || {
+            let configured = configured_parameters().map_err(|e| e.to_string())?;
+            Ok::<_, String>((configured, worker.lock().unwrap().parameters()))
+        })
+        .await
+        .map_err(|e| Status::internal(e.to_string()))?
+        .map_err(Status::internal)?;
+
+        let mut names = configured.iter().chain(running.iter()).cloned().collect::<Vec<_>>();
+        names.sort();
+        names.dedup();
+        let parameters = names
+            .into_iter()
+            .map(|name| ParameterInfo {
+                configured: configured.contains(&name),
+                running: running.contains(&name),
+                name,
+            })
+            .collect();
+        Ok(Response::new(ListParametersResponse { parameters }))
+    }
+
+    async fn get_worker_state(
+        &self,
+        request: Request<ParameterRequest>,
+    ) -> Result<Response<WorkerStateResponse>, Status> {
+        let request = request.into_inner();
+        let (worker, station, parameter) =
+            (self.worker.clone(), request.station.clone(), request.parameter.clone());
+        let states = tokio::task::spawn_blocking(move || {
+            worker.lock().unwrap().states(station.as_deref(), parameter.as_deref())
+        })
+        .await
+        .map_err(|e| Status::internal(e.to_string()))?;
+        if let (Some(target), true) = (&request.parameter, states.is_empty()) {
+            return Err(Status::not_found(format!("Not exist: {target}")));
+        }
+        let states = states.into_iter().map(to_worker_state).collect();
+        Ok(Response::new(WorkerStateResponse { states }))
+    }
+
+    async fn reset_state(
+        &self,
+        request: Request<ParameterRequest>,
+    ) -> Result<Response<ParameterListResponse>, Status> {
+        let request = request.into_inner();
+        let worker = self.worker.clone();
+        let parameters = tokio::task::spawn_blocking(move || {
+            worker.lock().unwrap().reset(request.station.as_deref(), request.parameter.as_deref())
+        })
+        .await
+        .map_err(|e| Status::internal(e.to_string()))?;
+        Ok(Response::new(ParameterListResponse { parameters }))
+    }
+
+    async fn stop_parameter(
+        &self,
+        request: Request<ParameterRequest>,
+    ) -> Result<Response<ParameterListResponse>, Status> {
+        let request = request.into_inner();
+        let worker = self.worker.clone();
+        let parameters = tokio::task::spawn_blocking(move || {
+            worker
+                .lock()
+                .unwrap()
+                .stop(request.station.as_deref(), request.parameter.as_deref())
+                .map_err(|e| e.to_string())
+        })
+        .await
+        .map_err(|e| Status::internal(e.to_string()))?
+        .map_err(Status::failed_precondition)?;
+        Ok(Response::new(ParameterListResponse { parameters }))
+    }
+
+    async fn start_parameter(
+        &self,
+        request: Request<ParameterRequest>,
+    ) -> Result<Response<ParameterListResponse>, Status> {
+        let request = request.into_inner();
+        let worker = self.worker.clone();
+        let parameters = tokio::task::spawn_blocking(move || {
+            worker
+                .lock()
+                .unwrap()
+                .start(request.station.as_deref(), request.parameter.as_deref())
+                .map_err(|e| e.to_string())
+        })
+        .await
+        .map_err(|e| Status::internal(e.to_string()))?
+        .map_err(Status::failed_precondition)?;
+        Ok(Response::new(ParameterListResponse { parameters }))
+    }
+
+    async fn add_maintenance(
+        &self,
+        request: Request<MaintenanceWindow>,
+    ) -> Result<Response<MaintenanceList>, Status> {
+        let request = request.into_inner();
+        let window = crate::lib::maintenance::MaintenanceWindow::new(
+            &request.start,
+            &request.end,
+            request.station,
+            request.parameters,
+            request.reason,
+        )
+        .map_err(|e| Status::invalid_argument(e.to_string()))?;
+
+        let worker = self.worker.clone();
+        let list = tokio::task::spawn_blocking(move || {
+            let mut qc = worker.lock().unwrap();
+            qc.add_maintenance(window);
+            to_maintenance_list(qc.maintenance())
+        })
+        .await
+        .map_err(|e| Status::internal(e.to_string()))?;
+        Ok(Response::new(list))
+    }
+
+    async fn list_maintenance(
+        &self,
+        _request: Request<ListMaintenanceRequest>,
+    ) -> Result<Response<MaintenanceList>, Status> {
+        let worker = self.worker.clone();
+        let list = tokio::task::spawn_blocking(move || {
+            to_maintenance_list(worker.lock().unwrap().maintenance())
+        })
+        .await
+        .map_err(|e| Status::internal(e.to_string()))?;
+        Ok(Response::new(list))
+    }
+
+    async fn remove_maintenance(
+        &self,
+        request: Request<RemoveMaintenanceRequest>,
+    ) -> Result<Response<MaintenanceList>, Status> {
+        let index = request.into_inner().index as usize;
+        let worker = self.worker.clone();
+        let list = tokio::task::spawn_blocking(move || {
+            let mut qc = worker.lock().unwrap();
+            qc.remove_maintenance(index).map_err(|e| e.to_string())?;
+            Ok::<_, String>(to_maintenance_list(qc.maintenance()))
+        })
+        .await
+        .map_err(|e| Status::internal(e.to_string()))?
+        .map_err(Status::not_found)?;
+        Ok(Response::new(list))
+    }
+
+    async fn override_flags(
+        &self,
+        request: Request<OverrideRequest>,
+    ) -> Result<Response<OverrideResponse>, Status> {
+        let request = request.into_inner();
+        if request.user.trim().is_empty() || request.reason.trim().is_empty() {
+            return Err(Status::invalid_argument("user and reason are required"));
+        }
+        let filter = QueryFilter {
+            parameters: request.parameters,
+            start: parse_datetime(&request.start)
+                .map_err(|e| Status::invalid_argument(e.to_string()))?,
+            end: parse_datetime(&request.end)
+                .map_err(|e| Status::invalid_argument(e.to_string()))?,
+            station: request.station,
+            flag_mask: None,
+        };
+        let change = FlagOverride {
+            set: QCFlag::from_bits_retain(request.set),
+            clear: QCFlag::from_bits_retain(request.clear),
+            user: request.user,
+            reason: request.reason,
+        };
+
+        let worker = self.worker.clone();
+        let changed = tokio::task::spawn_blocking(move || {
+            worker.lock().unwrap().override_flags(&filter, &change).map_err(|e| e.to_string())
+        })
+        .await
+        .map_err(|e| Status::internal(e.to_string()))?
+        .map_err(Status::failed_precondition)?;
+        Ok(Response::new(OverrideResponse {
+            changed: changed as u64,
+        }))
+    }
+}
+
+#[tonic::async_trait]
+impl Health for HealthDaemon {
+    async fn check(
+        &self,
+        request: Request<HealthCheckRequest>,
+    ) -> Result<Response<HealthCheckResponse>, Status> {
+        let service = request.into_inner().service;
+        if !HEALTH_SERVICES.contains(&service.as_str()) {
+            return Err(Status::not_found(format!("Unknown service: {service}")));
+        }
+        Ok(Response::new(HealthCheckResponse {
+            status: *self.status.borrow() as i32,
+        }))
+    }
+
+    type WatchStream = ReceiverStream<Result<HealthCheckResponse, Status>>;
+
+    async fn watch(
+        &self,
+        request: Request<HealthCheckRequest>,
+    ) -> Result<Response<Self::WatchStream>, Status> {
+        let service = request.into_inner().service;
+        let mut status = self.status.subscribe();
+        let (tx, rx) = mpsc::channel(1);
+
+        tokio::spawn(async move {
+            if !HEALTH_SERVICES.contains(&service.as_str()) {
+                let _ = tx
+                    .send(Ok(HealthCheckResponse {
+                        status: ServingStatus::ServiceUnknown as i32,
+                    }))
+                    .await;
+                return;
+            }
+            loop {
+                let current = *status.borrow_and_update();
+                if tx
+                    .send(Ok(HealthCheckResponse {
+                        status: current as i32,
+                    }))
+                    .await
+                    .is_err()
+                {
+                    return;
+                }
+                if current == ServingStatus::NotServing {
+                    // only set on shutdown, end the call so the server can drain
+                    return;
+                }
+                tokio::select! {
+                    _ = tx.closed() => return,
+                    changed = status.changed() => if changed.is_err() {
+                        return;
+                    },
+                }
+            }
+        });
+
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn process_line_reports_values_and_errors() {
+        let mut qc = QCworker::new(HashMap::new());
+        let events = broadcast::channel(EVENT_BUFFER).0;
+
+        let line = process_line(&mut qc, 0, "humidity=50.0", &events);
+        assert_eq!(line.status, "Ok");
+        assert_eq!(line.values.len(), 1);
+        assert_eq!(line.values[0].parameter, "humidity");
+
+        let line = process_line(&mut qc, 1, "humidity", &events);
+        assert_eq!(line.index, 1);
+        assert_ne!(line.status, "Ok");
+        assert!(line.values.is_empty());
+    }
+
+    #[test]
+    fn summary_counts_lines() {
+        let mut qc = QCworker::new(HashMap::new());
+        let events = broadcast::channel(EVENT_BUFFER).0;
+        let mut summary = StreamSummary::default();
+        for (index, line) in ["humidity=50.0", "humidity", "humidity=51.0"].iter().enumerate() {
+            let line = process_line(&mut qc, index as u64, line, &events);
+            update_summary(&mut summary, &line);
+        }
+        assert_eq!(summary.received, 3);
+        assert_eq!(summary.succeeded, 2);
+        assert_eq!(summary.failed, 1);
+    }
+
+    #[test]
+    fn processed_values_published() {
+        let mut qc = QCworker::new(HashMap::new());
+        qc.set_station(Some("st1".to_string()));
+        let events = broadcast::channel(EVENT_BUFFER).0;
+        let mut rx = events.subscribe();
+
+        process_line(&mut qc, 0, "humidity=50.0", &events);
+        let row = rx.try_recv().unwrap();
+        assert_eq!(row.parameter, "humidity");
+        assert_eq!(row.station, "st1");
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn health_reports_services() {
+        let health = HealthDaemon::default();
+        let check = |service: &str| {
+            let request = Request::new(HealthCheckRequest {
+                service: service.to_string(),
+            });
+            let health = health.clone();
+            async move { health.check(request).await }
+        };
+
+        let status = check("qc.QC").await.unwrap().into_inner().status;
+        assert_eq!(status, ServingStatus::Serving as i32);
+        assert!(check("unknown").await.is_err());
+
+        health.set_serving(false);
+        let status = check("").await.unwrap().into_inner().status;
+        assert_eq!(status, ServingStatus::NotServing as i32);
+    }
+}
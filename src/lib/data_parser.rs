@@ -1,37 +1,64 @@
+use std::fmt::Display;
+
 use chrono::NaiveDateTime;
 
-#[derive(Debug, Clone)]
+use super::ERROR;
+
+#[derive(Debug, Clone, Default)]
+#[allow(clippy::upper_case_acronyms, dead_code)]
 pub enum DataType {
     Datetime(NaiveDateTime),
     Integer(i64),
     Float(f64),
     String(String),
+    #[default]
     NULL,
 }
 
-impl ToString for DataType {
-    fn to_string(&self) -> String {
+impl DataType {
+    /// Value as written to state files, `None` for datetimes and missing values.
+    pub fn to_toml(&self) -> Option<toml::Value> {
+        match self {
+            DataType::Integer(v) => Some(toml::Value::Integer(*v)),
+            DataType::Float(v) => Some(toml::Value::Float(*v)),
+            DataType::String(v) => Some(toml::Value::String(v.to_string())),
+            DataType::Datetime(_) | DataType::NULL => None,
+        }
+    }
+
+    pub fn from_toml(value: Option<toml::Value>) -> Self {
+        match value {
+            Some(toml::Value::Integer(v)) => DataType::Integer(v),
+            Some(toml::Value::Float(v)) => DataType::Float(v),
+            Some(toml::Value::String(v)) => DataType::String(v),
+            _ => DataType::NULL,
+        }
+    }
+}
+
+impl Display for DataType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             DataType::Datetime(v) => {
-                v.format("%Y-%m-%dT%H:%M:%S").to_string()
+                write!(f, "{}", v.format("%Y-%m-%dT%H:%M:%S"))
             },
             DataType::Integer(v) => {
-                format!("{}", v)
+                write!(f, "{}", v)
             },
             DataType::Float(v) => {
-                format!("{}", v)
+                write!(f, "{}", v)
             },
             DataType::String(v) => {
-                v.to_owned()
+                f.write_str(v)
             },
             DataType::NULL => {
-                "None".to_string()
+                f.write_str("None")
             }
         }
     }
 }
 
-pub fn data_parser_format(data_format: &Vec<String>, s: &str) -> Vec<(String, DataType)> {
+pub fn data_parser_format(data_format: &[String], s: &str) -> Result<Vec<(String, DataType)>, ERROR> {
     let mut res = Vec::new();
     let ele = s.split(',').collect::<Vec<_>>();
 
@@ -41,7 +68,8 @@ pub fn data_parser_format(data_format: &Vec<String>, s: &str) -> Vec<(String, Da
                 res.push((
                     key.to_string(), 
                     DataType::Datetime(
-                        NaiveDateTime::parse_from_str(val, "%Y-%m-%dT%H:%M:%S").expect(&format!("Unspport datetime format: {}", val))
+                        NaiveDateTime::parse_from_str(val, "%Y-%m-%dT%H:%M:%S")
+                            .map_err(|_| format!("Unspport datetime format: {}", val))?
                     )
                 ));
             },
@@ -49,26 +77,49 @@ pub fn data_parser_format(data_format: &Vec<String>, s: &str) -> Vec<(String, Da
                 res.push((
                     key.to_string(), 
                     DataType::Float(
-                        val.parse::<f64>().expect(&format!("Unspport data format: {}", val))
+                        val.parse::<f64>().map_err(|_| format!("Unspport data format: {}", val))?
                     )
                 ));
             }
         }
     }
-    res
+    Ok(res)
 }
 
-pub fn data_parser_key_value(s: &str) -> Vec<(String, DataType)> {
+pub fn data_parser_key_value(s: &str) -> Result<Vec<(String, DataType)>, ERROR> {
     let mut res = Vec::new();
     let tmp = s.split(',').collect::<Vec<_>>();
 
     for ele in tmp {
-        let (key, val) = ele.split_at(ele.find('=').expect(&format!("Invalid data: {}", ele)));
-        let v = val[1..].parse::<f64>().expect(&format!("Unspport data format: {}", ele));
+        let (key, val) = ele.split_at(ele.find('=').ok_or_else(|| format!("Invalid data: {}", ele))?);
+        let v = val[1..].parse::<f64>().map_err(|_| format!("Unspport data format: {}", ele))?;
         res.push((
             key.to_string(), 
             DataType::Float(v)
         ));
     }
-    res
+    Ok(res)
+}
+
+/// Prepend the formation id to the payload, as `handler` expects: `F{n},{payload}`.
+pub fn with_protocol(protocol: Option<u32>, payload: &str) -> String {
+    if let Some(fidx) = protocol {
+        format!("F{fidx},{payload}")
+    } else {
+        payload.to_string()
+    }
+}
+
+/// Parse a datetime given on the command line or over RPC.
+/// Accepts `%Y-%m-%dT%H:%M:%S`, `%Y-%m-%d %H:%M:%S` and `%Y-%m-%d` (midnight).
+pub fn parse_datetime(s: &str) -> Result<NaiveDateTime, ERROR> {
+    let s = s.trim();
+    for fmt in ["%Y-%m-%dT%H:%M:%S", "%Y-%m-%d %H:%M:%S"] {
+        if let Ok(dt) = NaiveDateTime::parse_from_str(s, fmt) {
+            return Ok(dt);
+        }
+    }
+    let date = chrono::NaiveDate::parse_from_str(s, "%Y-%m-%d")
+        .map_err(|_| format!("Unspport datetime format: {}", s))?;
+    Ok(date.and_hms_opt(0, 0, 0).unwrap())
 }
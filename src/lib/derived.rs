@@ -0,0 +1,255 @@
+use chrono::NaiveDateTime;
+use evalexpr::Node;
+use serde_derive::Deserialize;
+
+use super::{
+    config_parser::ModuleType, data_parser::DataType, expr, py_module::PythonModule, ERROR,
+};
+
+pub const DERIVED_CONFIG: &str = "config/derived.toml";
+
+type Function = fn(f64, f64) -> f64;
+
+/// Derivations built in, `function = "{name}"` with its two arguments in `parameters`.
+const FUNCTIONS: [(&str, Function); 3] = [
+    ("dew_point", expr::dewpoint),
+    ("wind_u", expr::wind_u),
+    ("wind_v", expr::wind_v),
+];
+
+/// `config/derived.toml`, parameters computed from the fields of a line. They are added to the
+/// line after parsing and checked and stored like the received ones, with `config/{name}.toml`.
+#[derive(Debug, Deserialize, Default)]
+pub struct DerivedConfig {
+    #[serde(default)]
+    pub derived: Vec<Derived>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Derived {
+    pub name: String,
+    /// `F{n}` of the formation table, every line when empty
+    #[serde(default)]
+    pub formations: Vec<String>,
+    /// fields the value needs, it is not computed when one is missing
+    #[serde(default)]
+    pub parameters: Vec<String>,
+    /// e.g. `dewpoint(temperature, humidity)`, see `expr::context` for the functions
+    pub expr: Option<String>,
+    /// or `derive(datetime, data)` of a python module
+    pub module_type: Option<String>,
+    pub path: Option<String>,
+    /// or one of `FUNCTIONS`, e.g. `dew_point`
+    pub function: Option<String>,
+    #[serde(skip)]
+    node: Option<Node>,
+    #[serde(skip)]
+    builtin: Option<Function>,
+    #[serde(skip)]
+    instance: Option<PythonModule>,
+}
+
+impl DerivedConfig {
+    /// Nothing is derived when the file does not exist.
+    pub fn load(path: &str) -> Result<Self, ERROR> {
+        if !std::path::Path::new(path).exists() {
+            return Ok(DerivedConfig::default());
+        }
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| format!("Can't open config file {path}: {e}"))?;
+        let mut config: DerivedConfig =
+            toml::from_str(&contents).map_err(|e| format!("Invalid config {path}: {e}"))?;
+        for derived in config.derived.iter_mut() {
+            match (&derived.expr, &derived.module_type, &derived.path, &derived.function) {
+                (Some(v), None, _, None) => derived.node = Some(expr::compile(v)?),
+                (None, Some(module_type), Some(_), None)
+                    if ModuleType::from(module_type) == ModuleType::Python => {}
+                (None, None, _, Some(function)) => {
+                    let builtin = FUNCTIONS
+                        .iter()
+                        .find(|(name, _)| name == function)
+                        .ok_or_else(|| format!("{} in {path}: unknown function {function}", derived.name))?;
+                    if derived.parameters.len() != 2 {
+                        return Err(format!(
+                            "{} in {path}: {function} needs its two arguments in parameters",
+                            derived.name
+                        )
+                        .into());
+                    }
+                    derived.builtin = Some(builtin.1);
+                }
+                _ => {
+                    return Err(format!(
+                        "{} in {path}: needs either expr, function or a python module_type and path",
+                        derived.name
+                    )
+                    .into())
+                }
+            }
+        }
+        Ok(config)
+    }
+
+    /// Add the derived values to the fields of a line of `formation`, in the order of the config,
+    /// so one can use another. Values which fail are left out and returned with their error.
+    pub fn apply(
+        &mut self,
+        formation: Option<&str>,
+        datetime: &NaiveDateTime,
+        line: &mut Vec<(String, DataType)>,
+    ) -> Vec<(String, ERROR)> {
+        let mut failed = Vec::new();
+        for derived in self.derived.iter_mut() {
+            if !derived.formations.is_empty()
+                && !formation.is_some_and(|v| derived.formations.iter().any(|f| f == v))
+            {
+                continue;
+            }
+            if !derived
+                .parameters
+                .iter()
+                .all(|parameter| line.iter().any(|(key, _)| key == parameter))
+            {
+                continue;
+            }
+            match derived.value(datetime, line) {
+                Ok(value) => line.push((derived.name.to_string(), value)),
+                Err(e) => failed.push((derived.name.to_string(), e)),
+            }
+        }
+        failed
+    }
+}
+
+impl Derived {
+    fn value(
+        &mut self,
+        datetime: &NaiveDateTime,
+        line: &[(String, DataType)],
+    ) -> Result<DataType, ERROR> {
+        if let Some(node) = &self.node {
+            let context = expr::context(line)?;
+            return expr::from_value(node.eval_with_context(&context)?);
+        }
+        if let Some(function) = self.builtin {
+            // `load` checked there are two, `apply` that they are in the line
+            let mut args = Vec::new();
+            for parameter in &self.parameters {
+                let (_, value) = line.iter().find(|(key, _)| key == parameter).unwrap();
+                args.push(match value {
+                    DataType::Integer(v) => *v as f64,
+                    DataType::Float(v) => *v,
+                    _ => return Err(format!("{parameter} is not a number: {value}").into()),
+                });
+            }
+            return Ok(DataType::Float(function(args[0], args[1])));
+        }
+        if self.instance.is_none() {
+            let path = self.path.as_deref().unwrap_or_default();
+            self.instance = Some(PythonModule::new(&self.name, path)?);
+        }
+        // the python module gets the fields it needs, all of them when not given
+        let values = line
+            .iter()
+            .filter(|(key, _)| self.parameters.is_empty() || self.parameters.contains(key))
+            .cloned()
+            .collect::<Vec<_>>();
+        self.instance.as_ref().unwrap().derive(datetime, &values)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn derive_expr_python_and_builtin() {
+        let path = std::env::temp_dir().join("derived_case1.toml");
+        std::fs::write(
+            &path,
+            r#"
+            [[derived]]
+            name = "dewpoint"
+            parameters = ["temperature", "humidity"]
+            expr = "dewpoint(temperature, humidity)"
+
+            [[derived]]
+            name = "spread"
+            formations = ["F1"]
+            parameters = ["temperature", "dewpoint"]
+            module_type = "python"
+            path = "./module/python/spread.py"
+
+            [[derived]]
+            name = "broken"
+            expr = "missing * 2"
+
+            [[derived]]
+            name = "dew_point"
+            parameters = ["temperature", "humidity"]
+            function = "dew_point"
+            "#,
+        )
+        .unwrap();
+        let mut config = DerivedConfig::load(path.to_str().unwrap()).unwrap();
+        let datetime =
+            NaiveDateTime::parse_from_str("2023-01-02T00:00:00", "%Y-%m-%dT%H:%M:%S").unwrap();
+        let mut line = vec![
+            ("temperature".to_string(), DataType::Float(20.0)),
+            ("humidity".to_string(), DataType::Float(50.0)),
+        ];
+        let failed = config.apply(Some("F1"), &datetime, &mut line);
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].0, "broken");
+        assert_eq!(
+            line.iter().map(|(key, _)| key.as_str()).collect::<Vec<_>>(),
+            ["temperature", "humidity", "dewpoint", "spread", "dew_point"]
+        );
+        assert!(matches!(line[3].1, DataType::Float(v) if (v - 10.74).abs() < 0.01));
+        assert_eq!(line[4].1.to_string(), line[2].1.to_string());
+
+        // other formations
+        let mut line = line[..2].to_vec();
+        config.apply(Some("F0"), &datetime, &mut line);
+        assert_eq!(line.len(), 4);
+
+        std::fs::write(
+            &path,
+            "[[derived]]\nname = \"x\"\nmodule_type = \"c\"\npath = \"x.so\"",
+        )
+        .unwrap();
+        assert!(DerivedConfig::load(path.to_str().unwrap()).is_err());
+        for function in ["function = \"dew\"", "function = \"wind_u\"\nparameters = [\"speed\"]"] {
+            std::fs::write(&path, format!("[[derived]]\nname = \"x\"\n{function}")).unwrap();
+            assert!(DerivedConfig::load(path.to_str().unwrap()).is_err());
+        }
+    }
+
+    #[test]
+    fn builtin_wind_components() {
+        let path = std::env::temp_dir().join("derived_wind.toml");
+        std::fs::write(
+            &path,
+            "[[derived]]\nname = \"u\"\nparameters = [\"speed\", \"direction\"]\nfunction = \"wind_u\"\n\
+             [[derived]]\nname = \"v\"\nparameters = [\"speed\", \"direction\"]\nfunction = \"wind_v\"",
+        )
+        .unwrap();
+        let mut config = DerivedConfig::load(path.to_str().unwrap()).unwrap();
+        let datetime =
+            NaiveDateTime::parse_from_str("2023-01-02T00:00:00", "%Y-%m-%dT%H:%M:%S").unwrap();
+        // from the west
+        let mut line = vec![
+            ("speed".to_string(), DataType::Integer(10)),
+            ("direction".to_string(), DataType::Float(270.0)),
+        ];
+        assert!(config.apply(None, &datetime, &mut line).is_empty());
+        assert!(matches!(line[2].1, DataType::Float(v) if (v - 10.0).abs() < 1e-9));
+        assert!(matches!(line[3].1, DataType::Float(v) if v.abs() < 1e-9));
+
+        let mut line = vec![
+            ("speed".to_string(), DataType::String("calm".to_string())),
+            ("direction".to_string(), DataType::Float(270.0)),
+        ];
+        assert_eq!(config.apply(None, &datetime, &mut line).len(), 2);
+    }
+}
@@ -0,0 +1,393 @@
+use std::{collections::BTreeMap, fs::File, io::Write, sync::Arc};
+
+use chrono::NaiveDateTime;
+use parquet::{
+    basic::{ConvertedType, Repetition, TimeUnit, Type as PhysicalType},
+    column::writer::ColumnWriter,
+    data_type::ByteArray,
+    file::{properties::WriterProperties, writer::SerializedFileWriter},
+    format::MilliSeconds,
+    schema::types::Type,
+};
+use serde_json::{Map, Number, Value};
+
+use super::{data_parser::DataType, qc_worker::QCFlag, storage::Record, ERROR};
+
+/// What `Table::new` does with values which have an error bit set.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum ErrorValues {
+    /// export them as they are
+    #[default]
+    Keep,
+    /// leave out their rows (their cells, when pivoted)
+    Drop,
+    /// export them with an empty value
+    Blank,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum ExportFormat {
+    #[default]
+    Csv,
+    /// an array with an object per row
+    Json,
+    Parquet,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ExportOptions {
+    pub errors: ErrorValues,
+    /// flag as `warn_levels`, `error_levels` and a column per status bit instead of its bits
+    pub decode_flags: bool,
+    /// a row per datetime and station with the columns of every parameter
+    pub pivot: bool,
+}
+
+/// Rows to export, every row has a cell per column, `NULL` when empty.
+#[derive(Debug, Default)]
+pub struct Table {
+    pub columns: Vec<String>,
+    pub rows: Vec<Vec<DataType>>,
+}
+
+/// Names of the flag columns, `prefix` is the parameter of pivoted tables.
+fn flag_columns(prefix: &str, decode: bool) -> Vec<String> {
+    let names: &[&str] = if decode {
+        &["warn_levels", "error_levels", "gap", "late", "maintenance", "not_evaluated", "invalid"]
+    } else {
+        &["flag"]
+    };
+    names
+        .iter()
+        .map(|name| match prefix {
+            "" => name.to_string(),
+            prefix => format!("{prefix}_{name}"),
+        })
+        .collect()
+}
+
+fn flag_cells(flag: &QCFlag, decode: bool) -> Vec<DataType> {
+    let levels = |levels: Vec<usize>| {
+        DataType::String(
+            levels
+                .iter()
+                .map(|level| level.to_string())
+                .collect::<Vec<_>>()
+                .join(";"),
+        )
+    };
+    if decode {
+        vec![
+            levels(flag.warn_levels()),
+            levels(flag.error_levels()),
+            DataType::Integer(flag.contains(QCFlag::Gap) as i64),
+            DataType::Integer(flag.contains(QCFlag::Late) as i64),
+            DataType::Integer(flag.contains(QCFlag::Maintenance) as i64),
+            DataType::Integer(flag.contains(QCFlag::NotEvaluated) as i64),
+            DataType::Integer(flag.is_invalid() as i64),
+        ]
+    } else {
+        // toml and sqlite have no unsigned integer either
+        vec![DataType::Integer(flag.bits() as i64)]
+    }
+}
+
+impl Table {
+    /// Table of `records`, which are in time order as `Store::query` returns them.
+    pub fn new(records: Vec<Record>, options: &ExportOptions) -> Self {
+        let records = records
+            .into_iter()
+            .filter(|record| !(options.errors == ErrorValues::Drop && record.flag.is_error()))
+            .map(|mut record| {
+                if options.errors == ErrorValues::Blank && record.flag.is_error() {
+                    record.value = DataType::NULL;
+                }
+                record
+            })
+            .collect::<Vec<_>>();
+        if options.pivot {
+            return Table::pivot(records, options.decode_flags);
+        }
+
+        let mut columns = ["datetime", "parameter", "station", "value"]
+            .map(|v| v.to_string())
+            .to_vec();
+        columns.extend(flag_columns("", options.decode_flags));
+        let rows = records
+            .into_iter()
+            .map(|record| {
+                let mut row = vec![
+                    DataType::Datetime(record.datetime),
+                    DataType::String(record.parameter),
+                    record.station.map_or(DataType::NULL, DataType::String),
+                    record.value,
+                ];
+                row.extend(flag_cells(&record.flag, options.decode_flags));
+                row
+            })
+            .collect();
+        Table { columns, rows }
+    }
+
+    fn pivot(records: Vec<Record>, decode: bool) -> Self {
+        let mut parameters = records
+            .iter()
+            .map(|record| record.parameter.to_string())
+            .collect::<Vec<_>>();
+        parameters.sort();
+        parameters.dedup();
+        let width = 1 + flag_columns("", decode).len();
+
+        let mut columns = vec!["datetime".to_string(), "station".to_string()];
+        for parameter in &parameters {
+            columns.push(parameter.to_string());
+            columns.extend(flag_columns(parameter, decode));
+        }
+
+        let mut rows: BTreeMap<(NaiveDateTime, Option<String>), Vec<DataType>> = BTreeMap::new();
+        for record in records {
+            let row = rows
+                .entry((record.datetime, record.station.clone()))
+                .or_insert_with(|| vec![DataType::NULL; parameters.len() * width]);
+            let index = parameters.binary_search(&record.parameter).unwrap() * width;
+            row[index] = record.value;
+            for (i, cell) in flag_cells(&record.flag, decode).into_iter().enumerate() {
+                row[index + 1 + i] = cell;
+            }
+        }
+
+        let rows = rows
+            .into_iter()
+            .map(|((datetime, station), cells)| {
+                let mut row = vec![
+                    DataType::Datetime(datetime),
+                    station.map_or(DataType::NULL, DataType::String),
+                ];
+                row.extend(cells);
+                row
+            })
+            .collect();
+        Table { columns, rows }
+    }
+
+    pub fn write_csv<W: Write>(&self, writer: W) -> Result<(), ERROR> {
+        let mut writer = ::csv::Writer::from_writer(writer);
+        writer.write_record(&self.columns)?;
+        for row in &self.rows {
+            writer.write_record(row.iter().map(|cell| match cell {
+                DataType::NULL => String::new(),
+                cell => cell.to_string(),
+            }))?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    pub fn write_json<W: Write>(&self, mut writer: W) -> Result<(), ERROR> {
+        let rows = self
+            .rows
+            .iter()
+            .map(|row| {
+                self.columns
+                    .iter()
+                    .zip(row)
+                    .map(|(column, cell)| {
+                        let value = match cell {
+                            DataType::Integer(v) => Value::Number((*v).into()),
+                            // NaN has no json number
+                            DataType::Float(v) => Number::from_f64(*v).map_or(Value::Null, Value::Number),
+                            DataType::NULL => Value::Null,
+                            cell => Value::String(cell.to_string()),
+                        };
+                        (column.to_string(), value)
+                    })
+                    .collect::<Map<_, _>>()
+            })
+            .collect::<Vec<_>>();
+        serde_json::to_writer(&mut writer, &rows)?;
+        writeln!(writer)?;
+        Ok(())
+    }
+
+    /// Type of a parquet column, from the cells it has.
+    fn column_type(&self, index: usize) -> ColumnType {
+        let mut types = self
+            .rows
+            .iter()
+            .filter_map(|row| match row[index] {
+                DataType::Datetime(_) => Some(ColumnType::Timestamp),
+                DataType::Integer(_) => Some(ColumnType::Integer),
+                DataType::Float(_) => Some(ColumnType::Float),
+                DataType::String(_) => Some(ColumnType::Text),
+                DataType::NULL => None,
+            })
+            .collect::<Vec<_>>();
+        types.sort();
+        types.dedup();
+        match types.as_slice() {
+            [] => ColumnType::Text,
+            [single] => *single,
+            [ColumnType::Integer, ColumnType::Float] => ColumnType::Float,
+            _ => ColumnType::Text,
+        }
+    }
+
+    /// Write one parquet file, every column is optional.
+    pub fn write_parquet(&self, path: &str) -> Result<(), ERROR> {
+        let types = (0..self.columns.len())
+            .map(|i| self.column_type(i))
+            .collect::<Vec<_>>();
+        let mut fields = Vec::new();
+        for (name, column_type) in self.columns.iter().zip(&types) {
+            let builder = match column_type {
+                ColumnType::Timestamp => Type::primitive_type_builder(name, PhysicalType::INT64)
+                    .with_logical_type(Some(parquet::basic::LogicalType::Timestamp {
+                        is_adjusted_to_u_t_c: false,
+                        unit: TimeUnit::MILLIS(MilliSeconds {}),
+                    })),
+                ColumnType::Integer => Type::primitive_type_builder(name, PhysicalType::INT64),
+                ColumnType::Float => Type::primitive_type_builder(name, PhysicalType::DOUBLE),
+                ColumnType::Text => Type::primitive_type_builder(name, PhysicalType::BYTE_ARRAY)
+                    .with_converted_type(ConvertedType::UTF8),
+            };
+            fields.push(Arc::new(builder.with_repetition(Repetition::OPTIONAL).build()?));
+        }
+        let schema = Arc::new(Type::group_type_builder("export").with_fields(fields).build()?);
+        let props = Arc::new(WriterProperties::builder().build());
+
+        let mut writer = SerializedFileWriter::new(File::create(path)?, schema, props)?;
+        let mut row_group = writer.next_row_group()?;
+        let mut index = 0;
+        while let Some(mut column) = row_group.next_column()? {
+            let cells = self.rows.iter().map(|row| &row[index]);
+            let levels = cells
+                .clone()
+                .map(|cell| !matches!(cell, DataType::NULL) as i16)
+                .collect::<Vec<_>>();
+            match column.untyped() {
+                ColumnWriter::Int64ColumnWriter(writer) => {
+                    let values = cells
+                        .filter_map(|cell| match cell {
+                            DataType::Datetime(v) => Some(v.and_utc().timestamp_millis()),
+                            DataType::Integer(v) => Some(*v),
+                            _ => None,
+                        })
+                        .collect::<Vec<_>>();
+                    writer.write_batch(&values, Some(&levels), None)?;
+                }
+                ColumnWriter::DoubleColumnWriter(writer) => {
+                    let values = cells
+                        .filter_map(|cell| match cell {
+                            DataType::Integer(v) => Some(*v as f64),
+                            DataType::Float(v) => Some(*v),
+                            _ => None,
+                        })
+                        .collect::<Vec<_>>();
+                    writer.write_batch(&values, Some(&levels), None)?;
+                }
+                ColumnWriter::ByteArrayColumnWriter(writer) => {
+                    let values = cells
+                        .filter(|cell| !matches!(cell, DataType::NULL))
+                        .map(|cell| ByteArray::from(cell.to_string().as_str()))
+                        .collect::<Vec<_>>();
+                    writer.write_batch(&values, Some(&levels), None)?;
+                }
+                _ => unreachable!("no other column types are written"),
+            }
+            column.close()?;
+            index += 1;
+        }
+        row_group.close()?;
+        writer.close()?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum ColumnType {
+    Timestamp,
+    Integer,
+    Float,
+    Text,
+}
+
+#[cfg(test)]
+mod test {
+    use parquet::file::reader::{FileReader, SerializedFileReader};
+
+    use crate::lib::data_parser::parse_datetime;
+
+    use super::*;
+
+    #[test]
+    fn table_pivot_and_parquet() {
+        let datetime = parse_datetime("2023-01-02T00:00:00").unwrap();
+        let record = |parameter: &str, value: DataType, flag: QCFlag| Record {
+            datetime,
+            parameter: parameter.to_string(),
+            station: Some("st1".to_string()),
+            value,
+            flag,
+            ..Default::default()
+        };
+        let records = vec![
+            record("humidity", DataType::Integer(50), QCFlag::L0_Warn | QCFlag::L1_Warn),
+            record("temperature", DataType::Float(99.0), QCFlag::L0_Warn | QCFlag::L0_Error),
+        ];
+
+        let mut options = ExportOptions {
+            errors: ErrorValues::Drop,
+            ..Default::default()
+        };
+        let table = Table::new(records.clone(), &options);
+        assert_eq!(table.rows.len(), 1);
+        let mut out = Vec::new();
+        table.write_csv(&mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "datetime,parameter,station,value,flag\n2023-01-02T00:00:00,humidity,st1,50,3\n"
+        );
+
+        options.errors = ErrorValues::Blank;
+        options.decode_flags = true;
+        options.pivot = true;
+        let table = Table::new(records, &options);
+        assert_eq!(
+            table.columns,
+            [
+                "datetime",
+                "station",
+                "humidity",
+                "humidity_warn_levels",
+                "humidity_error_levels",
+                "humidity_gap",
+                "humidity_late",
+                "humidity_maintenance",
+                "humidity_not_evaluated",
+                "humidity_invalid",
+                "temperature",
+                "temperature_warn_levels",
+                "temperature_error_levels",
+                "temperature_gap",
+                "temperature_late",
+                "temperature_maintenance",
+                "temperature_not_evaluated",
+                "temperature_invalid",
+            ]
+        );
+        assert_eq!(table.rows.len(), 1);
+        let mut out = Vec::new();
+        table.write_json(&mut out).unwrap();
+        let json: Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(json[0]["humidity"], 50);
+        assert_eq!(json[0]["humidity_warn_levels"], "0;1");
+        assert_eq!(json[0]["temperature"], Value::Null);
+        assert_eq!(json[0]["temperature_error_levels"], "0");
+
+        let path = std::env::temp_dir().join("qc_export_case1.parquet");
+        table.write_parquet(path.to_str().unwrap()).unwrap();
+        let reader = SerializedFileReader::new(File::open(&path).unwrap()).unwrap();
+        assert_eq!(reader.metadata().file_metadata().num_rows(), 1);
+        assert_eq!(reader.metadata().file_metadata().schema_descr().num_columns(), 18);
+    }
+}
@@ -0,0 +1,289 @@
+use std::{cell::RefCell, collections::VecDeque};
+
+use chrono::NaiveDateTime;
+use evalexpr::{
+    ContextWithMutableFunctions, ContextWithMutableVariables, Function, HashMapContext, Node, Value,
+};
+
+use super::{data_parser::DataType, QCModule, RecordModule, ERROR};
+
+// values an expr check keeps for `history`
+const HISTORY: usize = 10;
+
+/// `module_type = "expr"`, a check written as an expression which is true when the value passes.
+/// Besides the functions of `context` it has the variables `value`, `datetime`, `level`,
+/// `previous`, `previous_datetime`, `elapsed` (seconds since the previous value), `history`
+/// (a tuple of the last values, oldest first) and the other fields of the line by name.
+/// For the first value `previous` is the value itself and `elapsed` is 0, as evalexpr evaluates
+/// both sides of `||` and `&&`.
+pub struct ExprModule {
+    node: Node,
+    // values this check has seen, in the order it saw them
+    history: RefCell<VecDeque<(NaiveDateTime, DataType)>>,
+    // uses the earlier values, so its history is saved with the worker state
+    stateful: bool,
+}
+
+impl ExprModule {
+    pub fn new(expr: &str) -> Result<Self, ERROR> {
+        let node = compile(expr)?;
+        let stateful = node.iter_variable_identifiers().any(|name| {
+            matches!(name, "previous" | "previous_datetime" | "elapsed" | "history")
+        });
+        Ok(ExprModule {
+            node,
+            history: RefCell::new(VecDeque::new()),
+            stateful,
+        })
+    }
+}
+
+impl QCModule for ExprModule {
+    fn run(&self, level: usize, datetime: &NaiveDateTime, data: &DataType) -> Result<bool, ERROR> {
+        self.run_with_fields(level, datetime, data, &[])
+    }
+
+    fn run_with_fields(
+        &self,
+        level: usize,
+        datetime: &NaiveDateTime,
+        data: &DataType,
+        fields: &[(String, DataType)],
+    ) -> Result<bool, ERROR> {
+        let mut context = context(fields)?;
+        let mut history = self.history.borrow_mut();
+        let (previous_datetime, previous) = history
+            .back()
+            .cloned()
+            .unwrap_or_else(|| (*datetime, data.clone()));
+        context.set_value("value".to_string(), to_value(data))?;
+        context.set_value(
+            "datetime".to_string(),
+            to_value(&DataType::Datetime(*datetime)),
+        )?;
+        context.set_value("level".to_string(), Value::Int(level as i64))?;
+        context.set_value("previous".to_string(), to_value(&previous))?;
+        context.set_value(
+            "previous_datetime".to_string(),
+            to_value(&DataType::Datetime(previous_datetime)),
+        )?;
+        context.set_value(
+            "elapsed".to_string(),
+            Value::Float((*datetime - previous_datetime).num_milliseconds() as f64 / 1000.0),
+        )?;
+        context.set_value(
+            "history".to_string(),
+            Value::Tuple(history.iter().map(|(_, v)| to_value(v)).collect()),
+        )?;
+        let result = self.node.eval_boolean_with_context(&context);
+
+        history.push_back((*datetime, data.clone()));
+        if history.len() > HISTORY {
+            history.pop_front();
+        }
+        Ok(result?)
+    }
+
+    /// The history as `[datetime, value]` pairs, `[datetime]` for a missing value.
+    fn save_state(&self) -> Option<toml::Value> {
+        if !self.stateful {
+            return None;
+        }
+        let history = self.history.borrow();
+        let values = history
+            .iter()
+            .map(|(datetime, data)| {
+                let mut pair = vec![toml::Value::String(
+                    datetime.format("%Y-%m-%dT%H:%M:%S%.f").to_string(),
+                )];
+                pair.extend(data.to_toml());
+                toml::Value::Array(pair)
+            })
+            .collect();
+        Some(toml::Value::Array(values))
+    }
+
+    fn restore_state(&self, state: &toml::Value) -> Result<(), ERROR> {
+        let mut history = VecDeque::new();
+        for pair in state.as_array().ok_or("Invalid expr state")? {
+            let pair = pair.as_array().ok_or("Invalid expr state")?;
+            let datetime = pair
+                .first()
+                .and_then(|v| v.as_str())
+                .ok_or("Invalid expr state")?;
+            history.push_back((
+                NaiveDateTime::parse_from_str(datetime, "%Y-%m-%dT%H:%M:%S%.f")?,
+                DataType::from_toml(pair.get(1).cloned()),
+            ));
+        }
+        *self.history.borrow_mut() = history;
+        Ok(())
+    }
+}
+
+/// Record checks see the values by parameter name, and `datetime` and `level`.
+impl RecordModule for ExprModule {
+    fn run_record(
+        &self,
+        level: usize,
+        datetime: &NaiveDateTime,
+        values: &[(String, DataType)],
+    ) -> Result<bool, ERROR> {
+        let mut context = context(values)?;
+        context.set_value(
+            "datetime".to_string(),
+            to_value(&DataType::Datetime(*datetime)),
+        )?;
+        context.set_value("level".to_string(), Value::Int(level as i64))?;
+        Ok(self.node.eval_boolean_with_context(&context)?)
+    }
+}
+
+/// Parse an expression once, it is evaluated for every line.
+pub fn compile(expr: &str) -> Result<Node, ERROR> {
+    Ok(evalexpr::build_operator_tree(expr)
+        .map_err(|e| format!("Invalid expression {expr}: {e}"))?)
+}
+
+/// Context with `values` as variables and the functions
+/// `dewpoint(temperature, humidity)`, `wind_u(speed, direction)` and `wind_v(speed, direction)`,
+/// besides the builtin ones of evalexpr like `math::sqrt`.
+pub fn context(values: &[(String, DataType)]) -> Result<HashMapContext, ERROR> {
+    let mut context = HashMapContext::new();
+    context.set_function(
+        "dewpoint".to_string(),
+        Function::new(|argument| {
+            let args = argument.as_fixed_len_tuple(2)?;
+            Ok(Value::Float(dewpoint(
+                args[0].as_number()?,
+                args[1].as_number()?,
+            )))
+        }),
+    )?;
+    context.set_function(
+        "wind_u".to_string(),
+        Function::new(|argument| {
+            let args = argument.as_fixed_len_tuple(2)?;
+            Ok(Value::Float(wind_u(args[0].as_number()?, args[1].as_number()?)))
+        }),
+    )?;
+    context.set_function(
+        "wind_v".to_string(),
+        Function::new(|argument| {
+            let args = argument.as_fixed_len_tuple(2)?;
+            Ok(Value::Float(wind_v(args[0].as_number()?, args[1].as_number()?)))
+        }),
+    )?;
+    for (key, value) in values {
+        context.set_value(key.to_string(), to_value(value))?;
+    }
+    Ok(context)
+}
+
+/// Magnus formula, °C from °C and %.
+pub fn dewpoint(temperature: f64, humidity: f64) -> f64 {
+    let (a, b) = (17.62, 243.12);
+    let gamma = (humidity / 100.0).ln() + a * temperature / (b + temperature);
+    b * gamma / (a - gamma)
+}
+
+/// Eastward component of the wind blowing from `direction` degrees.
+pub fn wind_u(speed: f64, direction: f64) -> f64 {
+    -speed * direction.to_radians().sin()
+}
+
+/// Northward component of the wind blowing from `direction` degrees.
+pub fn wind_v(speed: f64, direction: f64) -> f64 {
+    -speed * direction.to_radians().cos()
+}
+
+pub fn to_value(data: &DataType) -> Value {
+    match data {
+        DataType::Datetime(v) => Value::String(v.format("%Y-%m-%dT%H:%M:%S").to_string()),
+        DataType::Integer(v) => Value::Int(*v),
+        DataType::Float(v) => Value::Float(*v),
+        DataType::String(v) => Value::String(v.to_string()),
+        DataType::NULL => Value::Empty,
+    }
+}
+
+pub fn from_value(value: Value) -> Result<DataType, ERROR> {
+    match value {
+        Value::Int(v) => Ok(DataType::Integer(v)),
+        Value::Float(v) => Ok(DataType::Float(v)),
+        Value::String(v) => Ok(DataType::String(v)),
+        Value::Empty => Ok(DataType::NULL),
+        v => Err(format!("Unsupported expression result: {v}").into()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn context_functions() {
+        let values = vec![
+            ("temperature".to_string(), DataType::Float(20.0)),
+            ("humidity".to_string(), DataType::Float(50.0)),
+            ("speed".to_string(), DataType::Float(2.0)),
+            ("direction".to_string(), DataType::Float(90.0)),
+        ];
+        let context = context(&values).unwrap();
+        let eval = |expr: &str| compile(expr).unwrap().eval_with_context(&context).unwrap();
+        assert!((eval("dewpoint(temperature, humidity)").as_number().unwrap() - 9.26).abs() < 0.01);
+        assert!((eval("wind_u(speed, direction)").as_number().unwrap() + 2.0).abs() < 1e-9);
+        assert!(eval("wind_v(speed, direction)").as_number().unwrap().abs() < 1e-9);
+        assert_eq!(eval("temperature * 9 / 5 + 32"), Value::Float(68.0));
+        assert!(compile("(temperature").is_err());
+        assert!(from_value(Value::Boolean(true)).is_err());
+    }
+
+    #[test]
+    fn expr_module_checks() {
+        let datetime =
+            NaiveDateTime::parse_from_str("2023-01-02T00:00:00", "%Y-%m-%dT%H:%M:%S").unwrap();
+        let module = ExprModule::new("value >= 0 && value <= 100 && value != -999").unwrap();
+        assert!(module.run(0, &datetime, &DataType::Float(50.0)).unwrap());
+        assert!(!module.run(0, &datetime, &DataType::Float(-999.0)).unwrap());
+
+        let module = ExprModule::new("math::abs(value - previous) <= 0.5 * elapsed").unwrap();
+        let at = |seconds: i64| datetime + chrono::Duration::seconds(seconds);
+        assert!(module.run(0, &at(0), &DataType::Float(10.0)).unwrap());
+        assert!(module.run(0, &at(10), &DataType::Float(14.0)).unwrap());
+        assert!(!module.run(0, &at(20), &DataType::Float(24.0)).unwrap());
+
+        let module = ExprModule::new("len(history) < 2 && value < temperature").unwrap();
+        let fields = [("temperature".to_string(), DataType::Float(20.0))];
+        assert!(module
+            .run_with_fields(0, &datetime, &DataType::Float(10.0), &fields)
+            .unwrap());
+        // no temperature in the line
+        assert!(module.run(0, &at(10), &DataType::Float(10.0)).is_err());
+        assert!(!module
+            .run_with_fields(0, &at(20), &DataType::Float(10.0), &fields)
+            .unwrap());
+        // not a boolean
+        assert!(ExprModule::new("value")
+            .unwrap()
+            .run(0, &datetime, &DataType::Float(1.0))
+            .is_err());
+    }
+
+    #[test]
+    fn history_survives_save_and_restore() {
+        let datetime =
+            NaiveDateTime::parse_from_str("2023-01-02T00:00:00", "%Y-%m-%dT%H:%M:%S").unwrap();
+        let at = |seconds: i64| datetime + chrono::Duration::seconds(seconds);
+        assert!(ExprModule::new("value >= 0").unwrap().save_state().is_none());
+
+        let module = ExprModule::new("math::abs(value - previous) <= 0.5 * elapsed").unwrap();
+        module.run(0, &at(0), &DataType::Float(10.0)).unwrap();
+        let state = module.save_state().unwrap();
+        let restored = ExprModule::new("math::abs(value - previous) <= 0.5 * elapsed").unwrap();
+        restored.restore_state(&state).unwrap();
+        // compared with the value before the restart
+        assert!(!restored.run(0, &at(10), &DataType::Float(24.0)).unwrap());
+        assert!(restored.restore_state(&toml::Value::Integer(1)).is_err());
+    }
+}
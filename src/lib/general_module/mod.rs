@@ -24,7 +24,7 @@ impl QCModule for GeneralModule {
         let c_data = CString::new(data.to_string()).unwrap();
 
         unsafe {
-            let func: FuncRun = self.lib.get(b"run").expect("Cannot load fundtion");
+            let func: FuncRun = self.lib.get(b"run")?;
             func(c_level, c_datetime.as_ptr(), c_data.as_ptr());
         }
 
@@ -35,7 +35,7 @@ impl QCModule for GeneralModule {
 impl GeneralModule {
     pub fn new(path: &str) -> Result<Self, ERROR> {
         unsafe {
-            let lib = libloading::Library::new(path).expect("Cannot load share library");
+            let lib = libloading::Library::new(path)?;
             Ok(Self { lib })
         }
     }
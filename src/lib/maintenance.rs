@@ -0,0 +1,94 @@
+use chrono::NaiveDateTime;
+use serde::{Deserializer, Serializer};
+use serde_derive::{Deserialize, Serialize};
+
+use super::{data_parser::parse_datetime, ERROR};
+
+/// A period sensors are worked on, values in it are flagged `Maintenance`.
+/// `[[maintenance]]` of the daemon config, or added over the Admin service.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct MaintenanceWindow {
+    /// inclusive, e.g. "2023-01-02T08:00:00"
+    #[serde(serialize_with = "write_datetime", deserialize_with = "read_datetime")]
+    pub start: NaiveDateTime,
+    /// inclusive
+    #[serde(serialize_with = "write_datetime", deserialize_with = "read_datetime")]
+    pub end: NaiveDateTime,
+    /// every station when not given
+    pub station: Option<String>,
+    /// every parameter when empty
+    #[serde(default)]
+    pub parameters: Vec<String>,
+    #[serde(default)]
+    pub reason: String,
+}
+
+fn write_datetime<S: Serializer>(datetime: &NaiveDateTime, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(&datetime.format("%Y-%m-%dT%H:%M:%S"))
+}
+
+fn read_datetime<'de, D: Deserializer<'de>>(deserializer: D) -> Result<NaiveDateTime, D::Error> {
+    let s = <String as serde::Deserialize>::deserialize(deserializer)?;
+    parse_datetime(&s).map_err(serde::de::Error::custom)
+}
+
+impl MaintenanceWindow {
+    pub fn new(
+        start: &str,
+        end: &str,
+        station: Option<String>,
+        parameters: Vec<String>,
+        reason: String,
+    ) -> Result<Self, ERROR> {
+        let window = MaintenanceWindow {
+            start: parse_datetime(start)?,
+            end: parse_datetime(end)?,
+            station,
+            parameters,
+            reason,
+        };
+        if window.start > window.end {
+            return Err(format!("Maintenance ends before it starts: {start} - {end}").into());
+        }
+        Ok(window)
+    }
+
+    pub fn matches(&self, datetime: &NaiveDateTime, parameter: &str, station: Option<&str>) -> bool {
+        self.start <= *datetime
+            && *datetime <= self.end
+            && (self.station.is_none() || self.station.as_deref() == station)
+            && (self.parameters.is_empty() || self.parameters.iter().any(|v| v == parameter))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn window_matches() {
+        #[derive(Deserialize)]
+        struct Config {
+            maintenance: Vec<MaintenanceWindow>,
+        }
+        let config: Config = toml::from_str(
+            r#"
+            [[maintenance]]
+            start = "2023-01-02T08:00:00"
+            end = "2023-01-02T10:00:00"
+            station = "st1"
+            parameters = ["temperature"]
+            reason = "calibration"
+            "#,
+        )
+        .unwrap();
+        let window = &config.maintenance[0];
+        let datetime = parse_datetime("2023-01-02T09:00:00").unwrap();
+        assert!(window.matches(&datetime, "temperature", Some("st1")));
+        assert!(!window.matches(&datetime, "humidity", Some("st1")));
+        assert!(!window.matches(&datetime, "temperature", None));
+        assert!(!window.matches(&parse_datetime("2023-01-02T10:00:01").unwrap(), "temperature", Some("st1")));
+
+        assert!(MaintenanceWindow::new("2023-01-02", "2023-01-01", None, Vec::new(), String::new()).is_err());
+    }
+}
@@ -5,15 +5,56 @@ use chrono::NaiveDateTime;
 use self::data_parser::DataType;
 
 pub mod config_parser;
+pub mod daemon;
 pub mod data_parser;
+pub mod derived;
+pub mod export;
+pub mod expr;
 pub mod general_module;
+pub mod maintenance;
 pub mod py_module;
 pub mod qc_worker;
-pub mod database;
+pub mod record_check;
+pub mod storage;
 pub mod qc;
+#[path = "grpc.health.v1.rs"]
+pub mod health;
 
+#[allow(clippy::upper_case_acronyms)]
 pub type ERROR = Box<dyn Error + 'static>;
 
-pub trait QCModule {
+pub trait QCModule: Send {
     fn run(&self, level: usize, datetime: &NaiveDateTime, data: &DataType) -> Result<bool, ERROR>;
+
+    /// `fields` are the other values of the line, only `expr` modules use them.
+    fn run_with_fields(
+        &self,
+        level: usize,
+        datetime: &NaiveDateTime,
+        data: &DataType,
+        _fields: &[(String, DataType)],
+    ) -> Result<bool, ERROR> {
+        self.run(level, datetime, data)
+    }
+
+    /// What the module learned from earlier values, kept in the worker state file.
+    /// `None` for modules without such state.
+    fn save_state(&self) -> Option<toml::Value> {
+        None
+    }
+
+    /// Take back what `save_state` returned.
+    fn restore_state(&self, _state: &toml::Value) -> Result<(), ERROR> {
+        Ok(())
+    }
+}
+
+/// Check over several values of one line, see `record_check`.
+pub trait RecordModule: Send {
+    fn run_record(
+        &self,
+        level: usize,
+        datetime: &NaiveDateTime,
+        values: &[(String, DataType)],
+    ) -> Result<bool, ERROR>;
 }
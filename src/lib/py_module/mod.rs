@@ -4,10 +4,10 @@ use chrono::NaiveDateTime;
 use pyo3::{
     exceptions::PyTypeError,
     prelude::*,
-    types::{IntoPyDict, PyBool, PyDict},
+    types::{IntoPyDict, PyBool, PyDict, PyLong, PyString},
 };
 
-use super::{data_parser::DataType, QCModule, ERROR};
+use super::{data_parser::DataType, QCModule, RecordModule, ERROR};
 
 pub struct PythonModule {
     name: String,
@@ -26,8 +26,36 @@ impl ToPyObject for DataType {
     }
 }
 
+impl std::fmt::Debug for PythonModule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "PythonModule({})", self.name)
+    }
+}
+
 impl QCModule for PythonModule {
     fn run(&self, level: usize, datetime: &NaiveDateTime, data: &DataType) -> Result<bool, ERROR> {
+        match self._run(level, datetime, |py| data.to_object(py)) {
+            Ok(status) => Ok(status),
+            Err(v) => Err(Box::new(v)),
+        }
+    }
+}
+
+/// `data` is a dict of the values, in the order of the check parameters.
+impl RecordModule for PythonModule {
+    fn run_record(
+        &self,
+        level: usize,
+        datetime: &NaiveDateTime,
+        values: &[(String, DataType)],
+    ) -> Result<bool, ERROR> {
+        let data = |py: Python<'_>| {
+            values
+                .iter()
+                .map(|(key, value)| (key, value.to_object(py)))
+                .into_py_dict(py)
+                .to_object(py)
+        };
         match self._run(level, datetime, data) {
             Ok(status) => Ok(status),
             Err(v) => Err(Box::new(v)),
@@ -37,10 +65,9 @@ impl QCModule for PythonModule {
 
 impl PythonModule {
     pub fn new<S: AsRef<Path> + Copy>(name: &str, path: S) -> Result<Self, ERROR> {
-        let mut file = File::open(path).expect("Can't open config file.");
+        let mut file = File::open(path)?;
         let mut src_code = String::new();
-        file.read_to_string(&mut src_code)
-            .expect("Failed to read file.");
+        file.read_to_string(&mut src_code)?;
 
         Ok(Self {
             name: name.to_string(),
@@ -48,7 +75,47 @@ impl PythonModule {
         })
     }
 
-    fn _run(&self, level: usize, datetime: &NaiveDateTime, data: &DataType) -> PyResult<bool> {
+    /// Value of a derived parameter, `derive(datetime, data)` returns `{"value": ...}`
+    /// with `data` a dict of the fields of the line.
+    pub fn derive(&self, datetime: &NaiveDateTime, values: &[(String, DataType)]) -> Result<DataType, ERROR> {
+        let result = Python::with_gil(|py| -> PyResult<DataType> {
+            let func: Py<PyAny> =
+                PyModule::from_code(py, &self.src_code, &format!("{}.py", self.name), &self.name)?
+                    .getattr("derive")?
+                    .into();
+
+            let mut map = HashMap::new();
+            map.insert("datetime", datetime.to_string().to_object(py));
+            map.insert(
+                "data",
+                values
+                    .iter()
+                    .map(|(key, value)| (key, value.to_object(py)))
+                    .into_py_dict(py)
+                    .to_object(py),
+            );
+
+            let pyobj = func.call(py, (), Some(map.into_py_dict(py)))?;
+            let res: &PyDict = pyobj.extract(py)?;
+            match res.get_item("value")? {
+                Some(v) if v.is_none() => Ok(DataType::NULL),
+                Some(v) if v.is_instance_of::<PyString>() => Ok(DataType::String(v.extract()?)),
+                Some(v) if v.is_instance_of::<PyLong>() && !v.is_instance_of::<PyBool>() => {
+                    Ok(DataType::Integer(v.extract()?))
+                }
+                Some(v) => Ok(DataType::Float(v.extract()?)),
+                None => PyResult::Err(PyTypeError::new_err("Missing return value")),
+            }
+        });
+        result.map_err(|e| e.into())
+    }
+
+    fn _run(
+        &self,
+        level: usize,
+        datetime: &NaiveDateTime,
+        data: impl FnOnce(Python<'_>) -> PyObject,
+    ) -> PyResult<bool> {
         Python::with_gil(|py| {
             let func: Py<PyAny> =
                 PyModule::from_code(py, &self.src_code, &format!("{}.py", self.name), &self.name)?
@@ -61,7 +128,7 @@ impl PythonModule {
             let mut map = HashMap::new();
             map.insert("level", level.to_object(py));
             map.insert("datetime", datetime.to_string().to_object(py));
-            map.insert("data", data.to_object(py));
+            map.insert("data", data(py));
 
             let pyobj = func.call(py, (), Some(map.into_py_dict(py)))?;
             let res: &PyDict = pyobj.extract(py)?;
@@ -78,12 +145,12 @@ impl PythonModule {
 
 #[cfg(test)]
 mod test {
-    use std::{str::FromStr, thread::sleep};
+    use std::str::FromStr;
 
     use super::*;
     #[test]
     fn case1() {
-        let path = "./module/hello_world.py";
+        let path = "./module/python/boundary.py";
         let py = PythonModule::new("helloworld", path).unwrap();
 
         // println!("src code: {:?}", py.src_code);
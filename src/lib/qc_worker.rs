@@ -1,24 +1,47 @@
 use bitflags::bitflags;
-use chrono::NaiveDateTime;
+use chrono::{NaiveDateTime, Timelike};
 use serde_derive::{Deserialize, Serialize};
-use std::{collections::HashMap, fmt::Display, path::Path};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Display,
+    path::Path,
+    str::FromStr,
+};
 use toml::Table;
 
 use crate::{
     get_config,
-    lib::{config_parser::QCConfig, data_parser::data_parser_format},
+    lib::{
+        config_parser::{ExtModule, OrderPolicy, QCConfig},
+        data_parser::data_parser_format,
+    },
 };
 
 use super::{
     config_parser::ModuleType,
     data_parser::{data_parser_key_value, DataType},
+    derived::{DerivedConfig, DERIVED_CONFIG},
+    expr::ExprModule,
     general_module::GeneralModule,
+    maintenance::MaintenanceWindow,
     py_module::PythonModule,
-    ERROR, database::db_get,
+    record_check::{RecordConfig, RECORD_CONFIG},
+    storage::{
+        archive::{RawArchive, RawLine},
+        FlagOverride, QueryFilter, Record, SqliteStore, Store,
+    },
+    ERROR,
 };
 
 const ERROR_SHIFT: usize = 32;
+// warning bits 0..WARN_LEVELS, the bits up to ERROR_SHIFT tell the worker status
+pub const WARN_LEVELS: usize = 27;
+pub const CONFIG_ROOT: &str = "config";
+// files in CONFIG_ROOT which are not parameter configs
+const NON_PARAMETER_CONFIG: [&str; 4] = ["formation_table", "daemon", "record", "derived"];
 
+/// Init until the first value, Training while the checks collect `Global.training` samples,
+/// then Running. Stop (maintenance) is set and cleared by `QCworker::stop` and `start`.
 #[derive(Debug, Clone, Copy)]
 enum QCStatus {
     Unknown,
@@ -28,8 +51,8 @@ enum QCStatus {
     Stop,
 }
 
-// support 31 warning level, (0, 30)
-// lower 32 bit as warning bit
+// support 27 warning level, (0, 26)
+// lower 32 bit as warning bit, bit 27-31 as status bit
 // higher 32 bit as error bit, which set value as NAN
 bitflags! {
     #[derive(Debug, Clone, Copy, Default)]
@@ -43,8 +66,12 @@ bitflags! {
         const L5_Warn = 0b0010_0000;
         const L6_Warn = 0b0100_0000;
         const L7_Warn = 0b1000_0000;
+        const Gap = 1<<27;
+        const Late = 1<<28;
+        const Maintenance = 1<<29;
+        const NotEvaluated = 1<<30;
         const Invalid = 0b1000_0000_0000_0000_0000_0000_0000_0000;
-        const L0_Error = 1<<(ERROR_SHIFT + 0);
+        const L0_Error = 1<<ERROR_SHIFT;
         const L1_Error = 1<<(ERROR_SHIFT + 1);
         const L2_Error = 1<<(ERROR_SHIFT + 2);
         const L3_Error = 1<<(ERROR_SHIFT + 3);
@@ -68,8 +95,211 @@ impl QCFlag {
         *self.0.bits_mut() = 0;
     }
 
-    pub fn set_invalid(&mut self) {
-        *self.0.bits_mut() = QCFlag::Invalid.bits();
+    /// Levels with the warning bit set.
+    pub fn warn_levels(&self) -> Vec<usize> {
+        (0..WARN_LEVELS)
+            .filter(|level| self.bits() & (1 << level) != 0)
+            .collect()
+    }
+
+    /// Levels with the error bit set.
+    pub fn error_levels(&self) -> Vec<usize> {
+        (0..64 - ERROR_SHIFT)
+            .filter(|level| self.bits() & (1 << (level + ERROR_SHIFT)) != 0)
+            .collect()
+    }
+
+    /// Any error bit is set, the value should not be used.
+    pub fn is_error(&self) -> bool {
+        self.bits() >> ERROR_SHIFT != 0
+    }
+
+    pub fn is_invalid(&self) -> bool {
+        self.contains(QCFlag::Invalid)
+    }
+
+    /// What the set bits mean, with the modules of each level in the parameter `config`.
+    pub fn explain(&self, config: Option<&QCConfig>) -> Vec<FlagTerm> {
+        let modules = |level: usize| match config {
+            Some(config) if level <= config.max_level() => config
+                .members(level)
+                .module
+                .iter()
+                .flatten()
+                .map(|module| module.name.to_string())
+                .collect(),
+            _ => Vec::new(),
+        };
+
+        let mut terms = Vec::new();
+        for level in self.warn_levels() {
+            terms.push(FlagTerm {
+                kind: FlagKind::Warn,
+                level: Some(level),
+                modules: modules(level),
+            });
+        }
+        for (status, kind) in [
+            (QCFlag::Gap, FlagKind::Gap),
+            (QCFlag::Late, FlagKind::Late),
+            (QCFlag::Maintenance, FlagKind::Maintenance),
+            (QCFlag::NotEvaluated, FlagKind::NotEvaluated),
+            (QCFlag::Invalid, FlagKind::Invalid),
+        ] {
+            if self.contains(status) {
+                terms.push(FlagTerm {
+                    kind,
+                    level: None,
+                    modules: Vec::new(),
+                });
+            }
+        }
+        for level in self.error_levels() {
+            terms.push(FlagTerm {
+                kind: FlagKind::Error,
+                level: Some(level),
+                modules: modules(level),
+            });
+        }
+        terms
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlagKind {
+    /// a module of the level failed
+    Warn,
+    /// a module of a level with `errorflag` failed, the value should not be used
+    Error,
+    /// values were missing before this one, or a gap record without value
+    Gap,
+    /// older than the last checked value, stored without running the checks
+    Late,
+    /// received while the parameter was stopped
+    Maintenance,
+    /// received while the checks were training
+    NotEvaluated,
+    Invalid,
+}
+
+/// One set bit of a flag, see `QCFlag::explain`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FlagTerm {
+    pub kind: FlagKind,
+    pub level: Option<usize>,
+    /// modules configured for the level, one or more of them failed
+    pub modules: Vec<String>,
+}
+
+impl Display for FlagTerm {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.level {
+            Some(level) => write!(f, "L{level}_{:?}", self.kind)?,
+            None => write!(f, "{:?}", self.kind)?,
+        }
+        if !self.modules.is_empty() {
+            write!(f, " ({})", self.modules.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+/// `L0_Warn | L1_Error`, `Clear` when no bit is set.
+impl Display for QCFlag {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let terms = self.explain(None);
+        if terms.is_empty() {
+            return f.write_str("Clear");
+        }
+        let terms = terms.iter().map(|term| term.to_string()).collect::<Vec<_>>();
+        f.write_str(&terms.join(" | "))
+    }
+}
+
+/// Reads what `Display` writes, or the bits as a number.
+impl FromStr for QCFlag {
+    type Err = ERROR;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if let Ok(bits) = s.parse::<u64>() {
+            return Ok(QCFlag::from_bits_retain(bits));
+        }
+        if let Some(hex) = s.strip_prefix("0x") {
+            return Ok(QCFlag::from_bits_retain(u64::from_str_radix(hex, 16)?));
+        }
+
+        let mut flag = QCFlag::new();
+        for term in s.split('|').map(|term| term.trim()) {
+            let level = |suffix: &str| {
+                term.strip_prefix('L')
+                    .and_then(|v| v.strip_suffix(suffix))
+                    .and_then(|v| v.parse::<usize>().ok())
+            };
+            match (term, level("_Warn"), level("_Error")) {
+                ("Clear", _, _) => {}
+                ("Invalid", _, _) => flag |= QCFlag::Invalid,
+                ("Late", _, _) => flag |= QCFlag::Late,
+                ("Gap", _, _) => flag |= QCFlag::Gap,
+                ("Maintenance", _, _) => flag |= QCFlag::Maintenance,
+                ("NotEvaluated", _, _) => flag |= QCFlag::NotEvaluated,
+                (_, Some(level), _) if level < WARN_LEVELS => flag.set_bit(level),
+                (_, _, Some(level)) if level < 64 - ERROR_SHIFT => {
+                    flag.set_bit(level + ERROR_SHIFT)
+                }
+                _ => return Err(format!("Unknown flag: {term}").into()),
+            }
+        }
+        Ok(flag)
+    }
+}
+
+impl serde::Serialize for QCFlag {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for QCFlag {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct FlagVisitor;
+
+        impl serde::de::Visitor<'_> for FlagVisitor {
+            type Value = QCFlag;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.write_str("flag bits or terms like `L0_Warn | L1_Error`")
+            }
+
+            fn visit_u64<E: serde::de::Error>(self, v: u64) -> Result<QCFlag, E> {
+                Ok(QCFlag::from_bits_retain(v))
+            }
+
+            // toml and sqlite have no unsigned integer
+            fn visit_i64<E: serde::de::Error>(self, v: i64) -> Result<QCFlag, E> {
+                Ok(QCFlag::from_bits_retain(v as u64))
+            }
+
+            fn visit_str<E: serde::de::Error>(self, v: &str) -> Result<QCFlag, E> {
+                v.parse().map_err(|e: ERROR| E::custom(e))
+            }
+        }
+
+        deserializer.deserialize_any(FlagVisitor)
+    }
+}
+
+// a value held back and the fields of its line
+type Held<T> = (NaiveDateTime, T, Vec<(String, T)>);
+
+/// Station and parameter of a worker, stations never share QC state.
+pub type WorkerKey = (Option<String>, String);
+
+/// `parameter`, or `parameter@station` for the worker of a station.
+pub fn worker_name(station: Option<&str>, parameter: &str) -> String {
+    match station {
+        Some(station) => format!("{parameter}@{station}"),
+        None => parameter.to_string(),
     }
 }
 
@@ -79,30 +309,345 @@ struct WorkerInner<T> {
     data: Option<(NaiveDateTime, T)>,
     status: QCStatus,
     flag: QCFlag,
+    // `{level}:{module}={pass|fail|error|unloaded}` of the last value
+    outcomes: Vec<String>,
+    // values handled while training
+    samples: usize,
+    // values held back by `OrderPolicy::Buffer`, in time order, with the fields of their line
+    buffer: Vec<Held<T>>,
+    // late values dropped by `OrderPolicy::Reject`
+    rejected: u64,
+    duplicates: u64,
+    // of the last value, wall clock time for `gap_since`
+    received: Option<NaiveDateTime>,
+    // a gap record was made since the last value
+    gap: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct ModuleState {
+    pub level: usize,
+    pub name: String,
+    pub module_type: String,
+    pub loaded: bool,
+}
+
+/// Worker state written to disk, see `QCworker::save_state`.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct WorkerSnapshot {
+    pub parameter: String,
+    #[serde(default)]
+    pub station: Option<String>,
+    pub status: String,
+    pub datetime: Option<String>,
+    pub value: Option<toml::Value>,
+    // toml has no unsigned integer
+    pub flag: i64,
+    /// values seen while training
+    #[serde(default)]
+    pub samples: u64,
+    /// state of the modules which keep one, see `QCModule::save_state`
+    #[serde(default)]
+    pub modules: Vec<ModuleSnapshot>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ModuleSnapshot {
+    pub level: usize,
+    pub name: String,
+    pub state: toml::Value,
+}
+
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct StateFile {
+    pub saved: String,
+    pub workers: Vec<WorkerSnapshot>,
+    /// windows added over the Admin service, the config has the others
+    #[serde(default)]
+    pub maintenance: Vec<MaintenanceWindow>,
+}
+
+fn default_save_interval() -> u64 {
+    60
+}
+
+fn default_max_age() -> u64 {
+    3600
+}
+
+/// `[state]` of the daemon config.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct StateConfig {
+    /// seconds between writes of the state file, it is written on shutdown too
+    #[serde(default = "default_save_interval")]
+    pub save_interval: u64,
+    /// seconds, an older state file is not restored at startup; 0 restores any age
+    #[serde(default = "default_max_age")]
+    pub max_age: u64,
+}
+
+impl Default for StateConfig {
+    fn default() -> Self {
+        StateConfig {
+            save_interval: default_save_interval(),
+            max_age: default_max_age(),
+        }
+    }
+}
+
+/// Snapshot of one parameter worker, see `QCworker::state`.
+#[derive(Debug, Clone)]
+pub struct WorkerState {
+    pub parameter: String,
+    pub station: Option<String>,
+    pub status: String,
+    pub data: Option<(NaiveDateTime, DataType)>,
+    pub flag: QCFlag,
+    pub modules: Vec<ModuleState>,
+    /// late values dropped
+    pub rejected: u64,
+    /// values dropped for the timestamp of a checked or buffered one
+    pub duplicates: u64,
+    /// values held back to be checked in time order
+    pub buffered: usize,
 }
 
-#[derive(Debug)]
 pub struct QCworker {
     formation_table: HashMap<String, Vec<String>>,
-    map: HashMap<String, WorkerInner<DataType>>,
-    database: Option<String>,
+    map: HashMap<WorkerKey, WorkerInner<DataType>>,
+    database: Option<Box<dyn Store>>,
+    station: Option<String>,
+    // results not saved yet
+    pending: Vec<Record>,
+    // line being handled, its fields and archive id, stored with its values
+    raw: Option<String>,
+    line: Vec<(String, DataType)>,
+    raw_id: Option<String>,
+    archive: Option<RawArchive>,
+    // where the following lines come from, see `set_source`
+    source: Option<String>,
+    maintenance: Vec<MaintenanceWindow>,
+    // the first windows come from the daemon config, they are not saved with the state
+    configured_maintenance: usize,
+    // `config/record.toml` and `config/derived.toml`, loaded with the first line
+    record_checks: Option<RecordConfig>,
+    derived: Option<DerivedConfig>,
+}
+
+pub fn config_path(parameter: &str) -> String {
+    format!("{}/{}.toml", CONFIG_ROOT, parameter)
+}
+
+/// Values missing from `datetime` on, a record without value flagged `Gap`.
+fn gap_record(datetime: NaiveDateTime) -> Record {
+    Record {
+        datetime,
+        value: DataType::NULL,
+        flag: QCFlag::Gap,
+        outcomes: Some(String::new()),
+        ..Default::default()
+    }
 }
 
 impl WorkerInner<DataType> {
-    pub fn new(parameter: &str) -> Self {
-        WorkerInner {
-            config: QCConfig::new(&format!("config/{}.toml", parameter)),
+    pub fn new(parameter: &str) -> Result<Self, ERROR> {
+        Ok(WorkerInner {
+            config: QCConfig::load(&config_path(parameter))?,
             data: None,
             status: QCStatus::Init,
             flag: QCFlag::new(),
-        }
+            outcomes: Vec::new(),
+            samples: 0,
+            buffer: Vec::new(),
+            rejected: 0,
+            duplicates: 0,
+            received: None,
+            gap: false,
+        })
     }
 
     pub fn clean_flag(&mut self) {
         self.flag.clear_all();
+        self.outcomes.clear();
+    }
+
+    /// Take a received value as `Global.ordering` says, `fields` are the others of its line.
+    /// Returns the values checked now, with their flag and module outcomes; none when the value
+    /// is dropped or held back.
+    pub fn receive(
+        &mut self,
+        datetime: NaiveDateTime,
+        data: DataType,
+        fields: &[(String, DataType)],
+    ) -> Vec<Record> {
+        let last = self.data.as_ref().map(|(last, _)| *last);
+        if last == Some(datetime) || self.buffer.iter().any(|(v, _, _)| *v == datetime) {
+            self.duplicates += 1;
+            return Vec::new();
+        }
+
+        let ordering = self.config.ordering();
+        // the ordering changed on reload
+        let mut checked = if ordering != OrderPolicy::Buffer {
+            self.release()
+        } else {
+            Vec::new()
+        };
+        let late = last.is_some_and(|last| datetime < last);
+        match ordering {
+            OrderPolicy::Accept => checked.extend(self.check(datetime, data, fields)),
+            OrderPolicy::Reject if late => self.rejected += 1,
+            OrderPolicy::Flag | OrderPolicy::Buffer if late => checked.push(Record {
+                datetime,
+                value: data,
+                flag: QCFlag::Late,
+                outcomes: Some(String::new()),
+                ..Default::default()
+            }),
+            OrderPolicy::Buffer => {
+                let index = self.buffer.partition_point(|(v, _, _)| *v < datetime);
+                self.buffer.insert(index, (datetime, data, fields.to_vec()));
+                let newest = self.buffer.last().unwrap().0;
+                let until = newest - chrono::Duration::seconds(self.config.reorder_window());
+                let count = self.buffer.partition_point(|(v, _, _)| *v <= until);
+                for (datetime, data, fields) in self.buffer.drain(..count).collect::<Vec<_>>() {
+                    checked.extend(self.check(datetime, data, &fields));
+                }
+            }
+            _ => checked.extend(self.check(datetime, data, fields)),
+        }
+        checked
+    }
+
+    /// Check every value held back, see `receive`.
+    pub fn release(&mut self) -> Vec<Record> {
+        std::mem::take(&mut self.buffer)
+            .into_iter()
+            .flat_map(|(datetime, data, fields)| self.check(datetime, data, &fields))
+            .collect()
+    }
+
+    /// Run the checks on a value in time order. A value after a gap is flagged `Gap`, it comes
+    /// after a gap record when `gap_since` did not make one yet.
+    fn check(
+        &mut self,
+        datetime: NaiveDateTime,
+        data: DataType,
+        fields: &[(String, DataType)],
+    ) -> Vec<Record> {
+        let mut checked = Vec::new();
+        let mut after_gap = self.gap;
+        if let (Some(interval), Some(limit), Some((last, _))) =
+            (self.config.interval(), self.config.gap_after(), self.data.as_ref())
+        {
+            if !self.gap && datetime - *last > limit {
+                checked.push(gap_record(*last + interval));
+                after_gap = true;
+            }
+        }
+        self.gap = false;
+
+        self.clean_flag();
+        self.qc_handle(datetime, data.clone(), fields);
+        if after_gap {
+            self.flag |= QCFlag::Gap;
+        }
+        checked.push(Record {
+            datetime,
+            value: data,
+            flag: self.flag,
+            outcomes: Some(self.outcomes.join(";")),
+            ..Default::default()
+        });
+        checked
     }
 
-    pub fn qc_handle(&mut self, datetime: NaiveDateTime, data: DataType) {
+    /// State of the modules which keep one.
+    fn save_modules(&self) -> Vec<ModuleSnapshot> {
+        let mut modules = Vec::new();
+        for level in 0..=self.config.max_level() {
+            for module in self.config.members(level).module.iter().flatten() {
+                if let Some(state) = module.instance.as_ref().and_then(|v| v.save_state()) {
+                    modules.push(ModuleSnapshot {
+                        level,
+                        name: module.name.to_string(),
+                        state,
+                    });
+                }
+            }
+        }
+        modules
+    }
+
+    /// Give the modules which keep a state theirs from `saved`. False when one of them has
+    /// none there or it can't be restored.
+    fn restore_modules(&mut self, saved: &[ModuleSnapshot]) -> bool {
+        let mut complete = true;
+        for level in 0..=self.config.max_level() {
+            for module in self.config.members_mut(level).module.iter_mut().flatten() {
+                load_instance(module);
+                let Some(instance) = module.instance.as_ref() else {
+                    continue;
+                };
+                if instance.save_state().is_none() {
+                    continue;
+                }
+                match saved.iter().find(|v| v.level == level && v.name == module.name) {
+                    Some(snapshot) => {
+                        if let Err(e) = instance.restore_state(&snapshot.state) {
+                            eprintln!("State of module {} not restored: {e}", module.name);
+                            complete = false;
+                        }
+                    }
+                    None => complete = false,
+                }
+            }
+        }
+        complete
+    }
+
+    /// Records due at `now` when nothing was received for `Global.interval` and the tolerance:
+    /// the values held back, then a gap record after the last value. Nothing while stopped
+    /// or when the gap has a record already.
+    pub fn gap_since(&mut self, now: NaiveDateTime) -> Vec<Record> {
+        let (Some(interval), Some(limit), Some(received)) =
+            (self.config.interval(), self.config.gap_after(), self.received)
+        else {
+            return Vec::new();
+        };
+        if self.gap || matches!(self.status, QCStatus::Stop) || now - received <= limit {
+            return Vec::new();
+        }
+        let mut checked = self.release();
+        if let Some((last, _)) = self.data.as_ref() {
+            checked.push(gap_record(*last + interval));
+            self.gap = true;
+        }
+        checked
+    }
+
+    pub fn qc_handle(
+        &mut self,
+        datetime: NaiveDateTime,
+        data: DataType,
+        fields: &[(String, DataType)],
+    ) {
+        match self.status {
+            QCStatus::Stop => {
+                // maintenance, stored without running the checks
+                self.flag |= QCFlag::Maintenance;
+                self.data = Some((datetime, data));
+                return;
+            }
+            QCStatus::Init | QCStatus::Unknown => self.status = QCStatus::Training,
+            _ => {}
+        }
+        if let QCStatus::Training = self.status {
+            if self.samples >= self.config.training() {
+                self.status = QCStatus::Running;
+            }
+        }
+
         for level in 0..=self.config.max_level() {
             let level_pattern = self.config.members_mut(level);
 
@@ -112,56 +657,75 @@ impl WorkerInner<DataType> {
                     if module.module_type == ModuleType::Unknown {
                         continue;
                     }
-                    let is_instance = module.instance.is_some();
-                    if !is_instance {
-                        module.instance = match module.module_type {
-                            ModuleType::General => {
-                                if let Ok(inner) = GeneralModule::new(&module.path) {
-                                    Some(Box::new(inner))
-                                } else {
-                                    None
-                                }
-                            }
-                            ModuleType::Python => {
-                                if let Ok(inner) = PythonModule::new(&module.name, &module.path) {
-                                    Some(Box::new(inner))
-                                } else {
-                                    None
-                                }
-                            }
-                            _ => continue, // Never reach this arm
-                        };
-                    }
+                    load_instance(module);
 
                     // 規範 QCModule Interface
                     if let Some(qc) = module.instance.as_ref() {
-                        let result = match qc.run(level, &datetime, &data) {
-                            Ok(status) => status,
-                            Err(_) => {
-                                // TODO recode error
-                                false
-                            }
+                        // TODO recode error
+                        let result = qc.run_with_fields(level, &datetime, &data, fields);
+                        let outcome = match result {
+                            Ok(true) => "pass",
+                            Ok(false) => "fail",
+                            Err(_) => "error",
                         };
+                        self.outcomes.push(format!("{level}:{}={outcome}", module.name));
 
-                        if !result {
+                        if !result.unwrap_or(false) {
                             // failed case
-                            if let Some(errorflag) = level_pattern.errorflag {
-                                if errorflag {
-                                    self.flag.set_bit(level + ERROR_SHIFT);
-                                    // self.data = Some((datetime, DataType::NULL));
-                                    // return;
-                                }
+                            if module.errorflag || level_pattern.errorflag == Some(true) {
+                                self.flag.set_bit(level + ERROR_SHIFT);
+                                // self.data = Some((datetime, DataType::NULL));
+                                // return;
                             }
                             self.flag.set_bit(level);
                         }
+                    } else {
+                        self.outcomes.push(format!("{level}:{}=unloaded", module.name));
                     }
                 }
             }
         }
+
+        if let QCStatus::Training = self.status {
+            // the checks see the value to build their history, their result does not count yet
+            self.samples += 1;
+            self.flag = QCFlag::NotEvaluated;
+        }
         self.data = Some((datetime, data));
     }
 }
 
+/// Instantiate the module unless it already is, on first use or to restore its state.
+fn load_instance(module: &mut ExtModule) {
+    if module.instance.is_some() {
+        return;
+    }
+    module.instance = match module.module_type {
+        ModuleType::General => {
+            if let Ok(inner) = GeneralModule::new(&module.path) {
+                Some(Box::new(inner))
+            } else {
+                None
+            }
+        }
+        ModuleType::Python => {
+            if let Ok(inner) = PythonModule::new(&module.name, &module.path) {
+                Some(Box::new(inner))
+            } else {
+                None
+            }
+        }
+        ModuleType::Expr => {
+            if let Ok(inner) = ExprModule::new(module.expr.as_deref().unwrap_or_default()) {
+                Some(Box::new(inner))
+            } else {
+                None
+            }
+        }
+        _ => None,
+    };
+}
+
 impl QCworker {
     pub fn new(formation_table: HashMap<String, Vec<String>>) -> Self {
         let map = HashMap::new();
@@ -169,113 +733,561 @@ impl QCworker {
             formation_table,
             map,
             database: None,
+            station: None,
+            pending: Vec::new(),
+            raw: None,
+            line: Vec::new(),
+            raw_id: None,
+            archive: None,
+            source: None,
+            maintenance: Vec::new(),
+            configured_maintenance: 0,
+            record_checks: None,
+            derived: None,
         }
     }
 
-    fn change_status(&mut self, target: String, status: QCStatus) -> Result<(), ERROR> {
-        if let Some(val) = self.map.get_mut(&target) {
-            val.status = status;
+    /// Workers of `station` and `parameter`, every station or parameter when not given.
+    fn workers(&self, station: Option<&str>, parameter: Option<&str>) -> Vec<WorkerKey> {
+        let mut keys = self
+            .map
+            .keys()
+            .filter(|(s, p)| {
+                station.is_none_or(|v| s.as_deref() == Some(v)) && parameter.is_none_or(|v| p == v)
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+        keys.sort();
+        keys
+    }
+
+    /// Set the status of the matching running workers, a worker is created for `target` at
+    /// `station` when it has none yet. Returns the changed workers, see `worker_name`.
+    fn change_status(
+        &mut self,
+        station: Option<&str>,
+        target: Option<&str>,
+        status: QCStatus,
+    ) -> Result<Vec<String>, ERROR> {
+        let mut keys = self.workers(station, target);
+        if let (Some(v), true) = (target, keys.is_empty()) {
+            let key = (station.map(|v| v.to_string()), v.to_string());
+            self.map.insert(key.clone(), WorkerInner::new(v)?);
+            keys.push(key);
         }
-        Ok(())
+        for key in &keys {
+            let work = self.map.get_mut(key).unwrap();
+            work.status = match status {
+                // back to training when it was stopped before it had enough samples
+                QCStatus::Running if work.samples < work.config.training() => QCStatus::Training,
+                status => status,
+            };
+        }
+        Ok(keys.iter().map(|(s, p)| worker_name(s.as_deref(), p)).collect())
+    }
+
+    /// Stop QC for maintenance, values are stored flagged `Maintenance` until `start`.
+    pub fn stop(&mut self, station: Option<&str>, target: Option<&str>) -> Result<Vec<String>, ERROR> {
+        self.change_status(station, target, QCStatus::Stop)
     }
 
+    /// Resume QC after `stop`.
+    pub fn start(&mut self, station: Option<&str>, target: Option<&str>) -> Result<Vec<String>, ERROR> {
+        self.change_status(station, target, QCStatus::Running)
+    }
+
+    /// Check one value outside of a line, lines go through `handler`.
+    #[cfg(test)]
     pub fn append<S: AsRef<str> + Display>(
         &mut self,
         target: S,
         datetime: NaiveDateTime,
         data: DataType,
-    ) {
-        let mut entry = self
-            .map
-            .entry(target.to_string())
-            .or_insert(WorkerInner::new(target.as_ref()));
-        entry.clean_flag();
-        entry.qc_handle(datetime, data);
+    ) -> Result<(), ERROR> {
+        let key = (self.station.clone(), target.to_string());
+        if !self.map.contains_key(&key) {
+            self.map.insert(key.clone(), WorkerInner::new(target.as_ref())?);
+        }
+        self.receive(&key, datetime, data);
+        Ok(())
+    }
+
+    /// Check a value with the worker of `key`, which must exist.
+    fn receive(&mut self, key: &WorkerKey, datetime: NaiveDateTime, data: DataType) {
+        let entry = self.map.get_mut(key).unwrap();
+        entry.received = Some(chrono::offset::Local::now().naive_local());
+        let checked = entry.receive(datetime, data, &self.line);
+        self.push_checked(key, checked);
+    }
+
+    /// Add values checked by the worker of `key` to the results to save.
+    fn push_checked(&mut self, key: &WorkerKey, checked: Vec<Record>) {
+        let entry = &self.map[key];
+        let (station, parameter) = (key.0.as_deref(), key.1.as_str());
+        for mut record in checked {
+            if self
+                .maintenance
+                .iter()
+                .any(|window| window.matches(&record.datetime, parameter, station))
+            {
+                record.flag |= QCFlag::Maintenance;
+            }
+            self.pending.push(Record {
+                parameter: parameter.to_string(),
+                station: key.0.clone(),
+                config_version: entry.config.version().map(|v| v.to_string()),
+                config_hash: Some(entry.config.hash().to_string()),
+                raw: self.raw.clone(),
+                raw_id: self.raw_id.clone(),
+                // given by the store
+                revision: 0,
+                ..record
+            });
+        }
+    }
+
+    /// Check the values held back by `OrderPolicy::Buffer` of the matching workers.
+    pub fn release(&mut self, station: Option<&str>, target: Option<&str>) {
+        for key in self.workers(station, target) {
+            let checked = self.map.get_mut(&key).unwrap().release();
+            self.push_checked(&key, checked);
+        }
     }
 
+    /// Save into the daily sqlite files under `path`.
     pub fn set_database<S: AsRef<str> + Display>(&mut self, path: S) {
-        self.database = Some(path.to_string());
+        self.set_store(Box::new(SqliteStore::new(path)));
     }
 
-    pub fn show<S: AsRef<str> + Display>(&mut self, target: S) {
-        if let Some(work) = self.map.get(&target.to_string()) {
-            println!("{:#?}", work);
-        } else {
-            println!("Not exist: {:}", target);
+    pub fn set_store(&mut self, store: Box<dyn Store>) {
+        self.database = Some(store);
+    }
+
+    /// Station the following lines come from, stored along with the results.
+    pub fn set_station(&mut self, station: Option<String>) {
+        self.station = station;
+    }
+
+    pub fn station(&self) -> Option<&str> {
+        self.station.as_deref()
+    }
+
+    /// Keep every handled line, also those which fail to parse.
+    pub fn set_archive(&mut self, archive: RawArchive) {
+        self.archive = Some(archive);
+    }
+
+    /// Values in `window` are flagged `Maintenance`. Saved with the worker state.
+    pub fn add_maintenance(&mut self, window: MaintenanceWindow) {
+        self.maintenance.push(window);
+    }
+
+    /// Drop the window at `index` of `maintenance`.
+    pub fn remove_maintenance(&mut self, index: usize) -> Result<MaintenanceWindow, ERROR> {
+        if index >= self.maintenance.len() {
+            return Err(format!("No maintenance window {index}").into());
+        }
+        if index < self.configured_maintenance {
+            self.configured_maintenance -= 1;
         }
+        Ok(self.maintenance.remove(index))
     }
 
-    fn data_parse(&mut self, raw_data: &str) -> Option<Vec<(String, DataType)>> {
-        if raw_data.starts_with("F") {
-            let (protocol, payload) = raw_data.split_at(raw_data.find(",").unwrap());
-            let payload = payload.strip_prefix(",").unwrap();
-            // println!("protocol: {:?}, payload: {:?}", protocol, payload);
+    /// Windows of the daemon config, in place of those set before.
+    pub fn set_config_maintenance(&mut self, windows: Vec<MaintenanceWindow>) {
+        let count = windows.len();
+        self.maintenance.splice(..self.configured_maintenance, windows);
+        self.configured_maintenance = count;
+    }
 
-            let formation = self.formation_table.entry(protocol.to_string()).or_insert({
-                let path = "./config/formation_table.toml";
-                let cfg;
-                get_config!(cfg, path, FormationTable);
-                if let Some(v) = get_formations_table(&cfg, protocol) {
-                    v
-                } else {
-                    println!("TODO: return invalid information");
-                    return None;
+    pub fn maintenance(&self) -> &[MaintenanceWindow] {
+        &self.maintenance
+    }
+
+    /// Peer the following lines come from, stored in the raw archive.
+    pub fn set_source(&mut self, source: Option<String>) {
+        self.source = source;
+    }
+
+    /// Parameters with a running worker, at any station.
+    pub fn parameters(&self) -> Vec<String> {
+        let mut keys = self.map.keys().map(|(_, p)| p.to_string()).collect::<Vec<_>>();
+        keys.sort();
+        keys.dedup();
+        keys
+    }
+
+    /// State of the matching workers, every station or parameter when not given.
+    pub fn states(&self, station: Option<&str>, parameter: Option<&str>) -> Vec<WorkerState> {
+        self.workers(station, parameter)
+            .into_iter()
+            .filter_map(|(s, p)| self.state(s.as_deref(), p))
+            .collect()
+    }
+
+    /// State of the worker of `target` at `station`, `None` for values without station.
+    pub fn state<S: AsRef<str>>(&self, station: Option<&str>, target: S) -> Option<WorkerState> {
+        let work = self.map.get(&(station.map(|v| v.to_string()), target.as_ref().to_string()))?;
+        let mut modules = Vec::new();
+        for level in 0..=work.config.max_level() {
+            if let Some(module_list) = work.config.members(level).module.as_ref() {
+                for module in module_list {
+                    modules.push(ModuleState {
+                        level,
+                        name: module.name.to_string(),
+                        module_type: format!("{:?}", module.module_type),
+                        loaded: module.instance.is_some(),
+                    });
                 }
+            }
+        }
+
+        Some(WorkerState {
+            parameter: target.as_ref().to_string(),
+            station: station.map(|v| v.to_string()),
+            status: format!("{:?}", work.status),
+            data: work.data.clone(),
+            flag: work.flag,
+            modules,
+            rejected: work.rejected,
+            duplicates: work.duplicates,
+            buffered: work.buffer.len(),
+        })
+    }
+
+    /// Re-read the config of one or all running workers, modules are loaded again on next data.
+    /// QC state is kept. Returns the reloaded parameters.
+    pub fn reload_config(&mut self, target: Option<&str>) -> Result<Vec<String>, ERROR> {
+        let targets = match target {
+            Some(v) if self.map.keys().any(|(_, p)| p == v) => vec![v.to_string()],
+            Some(v) => {
+                // check the config before a worker is created for it
+                QCConfig::load(&config_path(v))?;
+                return Ok(vec![v.to_string()]);
+            }
+            None => self.parameters(),
+        };
+
+        // load everything first, so a broken config keeps the old ones
+        let (record_checks, derived) = match target {
+            Some(_) => (None, None),
+            None => (
+                Some(RecordConfig::load(RECORD_CONFIG)?),
+                Some(DerivedConfig::load(DERIVED_CONFIG)?),
+            ),
+        };
+        let mut configs = Vec::new();
+        for key in targets {
+            let config = QCConfig::load(&config_path(&key))?;
+            configs.push((key, config));
+        }
+
+        self.formation_table.clear();
+        if record_checks.is_some() {
+            self.record_checks = record_checks;
+            self.derived = derived;
+        }
+        let mut reloaded = Vec::new();
+        for (key, config) in configs {
+            // every station loads its own modules, a copy has none loaded
+            for ((_, parameter), work) in self.map.iter_mut() {
+                if *parameter == key {
+                    work.config = config.clone();
+                }
+            }
+            reloaded.push(key);
+        }
+        Ok(reloaded)
+    }
+
+    /// Write the state of every worker to `path`, periodically and when the daemon stops.
+    pub fn save_state<P: AsRef<Path>>(&self, path: P) -> Result<(), ERROR> {
+        let mut state = StateFile {
+            saved: chrono::offset::Local::now()
+                .naive_local()
+                .format("%Y-%m-%dT%H:%M:%S")
+                .to_string(),
+            workers: Vec::new(),
+            maintenance: self.maintenance[self.configured_maintenance..].to_vec(),
+        };
+        for key in self.workers(None, None) {
+            let work = &self.map[&key];
+            let (datetime, value) = match &work.data {
+                Some((datetime, data)) => (
+                    Some(datetime.format("%Y-%m-%dT%H:%M:%S").to_string()),
+                    data.to_toml(),
+                ),
+                None => (None, None),
+            };
+            state.workers.push(WorkerSnapshot {
+                parameter: key.1,
+                station: key.0,
+                status: format!("{:?}", work.status),
+                datetime,
+                value,
+                flag: work.flag.bits() as i64,
+                samples: work.samples as u64,
+                modules: work.save_modules(),
             });
-            Some(data_parser_format(formation, payload))
-        } else {
-            Some(data_parser_key_value(raw_data))
         }
+
+        let path = path.as_ref();
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        // write aside first, so a crash while writing keeps the previous state
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, toml::to_string(&state)?)?;
+        std::fs::rename(tmp, path)?;
+        Ok(())
     }
 
-    pub fn run(&mut self) {
-        let mut buffer = String::new();
-        loop {
-            buffer.clear();
-            std::io::stdin().read_line(&mut buffer).unwrap();
+    /// Restore what `save_state` wrote to `path`, unless it was saved more than `max_age`
+    /// seconds ago (0 for any age). Returns the restored workers, none without a file.
+    /// Workers whose config can't be loaded any more are skipped.
+    pub fn restore_state<P: AsRef<Path>>(&mut self, path: P, max_age: u64) -> Result<Vec<String>, ERROR> {
+        let path = path.as_ref();
+        if !path.exists() {
+            return Ok(Vec::new());
+        }
+        let state: StateFile = toml::from_str(&std::fs::read_to_string(path)?)
+            .map_err(|e| format!("Invalid state file {}: {e}", path.display()))?;
+        let saved = NaiveDateTime::parse_from_str(&state.saved, "%Y-%m-%dT%H:%M:%S")?;
+        let age = chrono::offset::Local::now().naive_local() - saved;
+        if max_age > 0 && age.num_seconds() > max_age as i64 {
+            return Ok(Vec::new());
+        }
+
+        self.maintenance.extend(state.maintenance);
 
-            match buffer.as_str().trim() {
-                "exit" | "q" | "quit" => {
-                    break;
+        let mut restored = Vec::new();
+        for snapshot in state.workers {
+            let mut work = match WorkerInner::new(&snapshot.parameter) {
+                Ok(v) => v,
+                Err(e) => {
+                    eprintln!("State of {} not restored: {e}", snapshot.parameter);
+                    continue;
                 }
-                "" => {}
-                s => {
-                    self.handler(s);
+            };
+            work.status = match snapshot.status.as_str() {
+                "Init" => QCStatus::Init,
+                "Training" => QCStatus::Training,
+                "Running" => QCStatus::Running,
+                "Stop" => QCStatus::Stop,
+                _ => QCStatus::Unknown,
+            };
+            work.samples = snapshot.samples as usize;
+            work.flag = QCFlag::from_bits_retain(snapshot.flag as u64);
+            if let Some(datetime) = &snapshot.datetime {
+                let data = DataType::from_toml(snapshot.value);
+                work.data = Some((NaiveDateTime::parse_from_str(datetime, "%Y-%m-%dT%H:%M:%S")?, data));
+            }
+            if !work.restore_modules(&snapshot.modules) {
+                // checks start from nothing, their results don't count until they learned again
+                if let QCStatus::Running = work.status {
+                    work.status = QCStatus::Training;
+                    work.samples = 0;
                 }
             }
+            restored.push(worker_name(snapshot.station.as_deref(), &snapshot.parameter));
+            self.map.insert((snapshot.station, snapshot.parameter), work);
         }
+        Ok(restored)
     }
 
-    pub fn handler(&mut self, raw_data: &str) {
+    /// Gap records of the workers which received nothing for too long until `now`, see
+    /// `WorkerInner::gap_since`. They are added to the results to save and returned.
+    pub fn check_gaps(&mut self, now: NaiveDateTime) -> Vec<Record> {
+        let start = self.pending.len();
+        for key in self.workers(None, None) {
+            let checked = self.map.get_mut(&key).unwrap().gap_since(now);
+            self.push_checked(&key, checked);
+        }
+        self.pending[start..].to_vec()
+    }
+
+    /// Drop the QC state of the matching workers, every station or parameter when not given.
+    /// Values held back are checked first. Returns the workers which were reset.
+    pub fn reset(&mut self, station: Option<&str>, target: Option<&str>) -> Vec<String> {
+        self.release(station, target);
+        let keys = self.workers(station, target);
+        for key in &keys {
+            self.map.remove(key);
+        }
+        keys.iter().map(|(s, p)| worker_name(s.as_deref(), p)).collect()
+    }
+
+    fn data_parse(&mut self, raw_data: &str) -> Result<Vec<(String, DataType)>, ERROR> {
+        if raw_data.starts_with('F') {
+            let (protocol, payload) =
+                raw_data.split_at(raw_data.find(',').ok_or("Missing payload")?);
+            let payload = payload.strip_prefix(',').unwrap();
+            // println!("protocol: {:?}, payload: {:?}", protocol, payload);
+
+            if !self.formation_table.contains_key(protocol) {
+                let path = "./config/formation_table.toml";
+                let cfg;
+                get_config!(cfg, path, FormationTable);
+                let formation = get_formations_table(&cfg, protocol)
+                    .ok_or_else(|| format!("Unknown formation: {}", protocol))?;
+                self.formation_table.insert(protocol.to_string(), formation);
+            }
+            data_parser_format(&self.formation_table[protocol], payload)
+        } else {
+            data_parser_key_value(raw_data)
+        }
+    }
+
+    /// Parse one raw line and run QC on every parameter in it.
+    /// Returns the records of this line, in the order they are saved.
+    pub fn handler(&mut self, raw_data: &str) -> Result<Vec<Record>, ERROR> {
         let current_datetime = chrono::offset::Local::now().naive_local();
-        if let Some(arr) = self.data_parse(raw_data) {
-            let datetime = if let Some(&(_, DataType::Datetime(dt))) = arr
-                .iter()
-                .filter(|(_, v)| match v {
-                    DataType::Datetime(_) => true,
-                    _ => false,
-                })
-                .next()
+        let raw_id = match self.archive.as_mut() {
+            Some(archive) => Some(archive.append(
+                current_datetime,
+                self.source.clone(),
+                self.station.clone(),
+                raw_data,
+            )?),
+            None => None,
+        };
+        let mut arr = self.data_parse(raw_data)?;
+        let datetime = if let Some(&(_, DataType::Datetime(dt))) = arr
+            .iter()
+            .find(|(_, v)| matches!(v, DataType::Datetime(_)))
+        {
+            dt
+        } else {
+            current_datetime
+        };
+        // `F{n}` of formation lines
+        let formation = raw_data.starts_with('F').then(|| raw_data.split(',').next().unwrap());
+        self.derive(formation, &datetime, &mut arr)?;
+        let start = self.check_line(formation, datetime, arr, Some(raw_data.to_string()), raw_id)?;
+
+        // nothing for values which were dropped or held back
+        Ok(self.pending[start..].to_vec())
+    }
+
+    /// Check every value of a parsed line, each seeing the others as fields, then run the record
+    /// checks. Returns where the results of the line start in `pending`. On error nothing of the
+    /// line is kept to save.
+    fn check_line(
+        &mut self,
+        formation: Option<&str>,
+        datetime: NaiveDateTime,
+        arr: Vec<(String, DataType)>,
+        raw: Option<String>,
+        raw_id: Option<String>,
+    ) -> Result<usize, ERROR> {
+        // everything which can fail comes before the first value is checked. Nothing of a line
+        // which fails is kept, or its values would be duplicate or late when it is resent
+        let mut workers: Vec<(WorkerKey, WorkerInner<DataType>)> = Vec::new();
+        for (target, data) in arr.iter() {
+            let key = (self.station.clone(), target.to_string());
+            if !matches!(data, DataType::Datetime(_))
+                && !self.map.contains_key(&key)
+                && !workers.iter().any(|(v, _)| *v == key)
             {
-                dt
-            } else {
-                current_datetime
+                workers.push((key, WorkerInner::new(target)?));
+            }
+        }
+        if self.record_checks.is_none() {
+            self.record_checks = Some(RecordConfig::load(RECORD_CONFIG)?);
+        }
+        self.map.extend(workers);
+
+        self.raw = raw;
+        self.raw_id = raw_id;
+        self.line = arr.clone();
+        let start = self.pending.len();
+        for (target, data) in arr.iter() {
+            if let DataType::Datetime(_) = data {
+                continue;
+            }
+            self.receive(&(self.station.clone(), target.to_string()), datetime, data.clone());
+        }
+        self.raw = None;
+        self.line.clear();
+        self.raw_id = None;
+        self.record_check(formation, datetime, &arr, start);
+        Ok(start)
+    }
+
+    /// Add the parameters of `config/derived.toml` to the fields of a line. A value which can't
+    /// be computed is left out, the rest of the line is still checked.
+    fn derive(
+        &mut self,
+        formation: Option<&str>,
+        datetime: &NaiveDateTime,
+        line: &mut Vec<(String, DataType)>,
+    ) -> Result<(), ERROR> {
+        if self.derived.is_none() {
+            self.derived = Some(DerivedConfig::load(DERIVED_CONFIG)?);
+        }
+        for (name, e) in self.derived.as_mut().unwrap().apply(formation, datetime, line) {
+            eprintln!("Failed to derive {name}: {e}");
+        }
+        Ok(())
+    }
+
+    /// Run the checks of `config/record.toml` on the values of a line, flagging the results
+    /// appended since `start` which have the datetime of the line and were checked. The checks
+    /// are loaded by `check_line`.
+    fn record_check(
+        &mut self,
+        formation: Option<&str>,
+        datetime: NaiveDateTime,
+        line: &[(String, DataType)],
+        start: usize,
+    ) {
+        for check in self.record_checks.as_mut().unwrap().check.iter_mut() {
+            let Some(values) = check.values(formation, line) else {
+                continue;
+            };
+            let result = check.run(&datetime, &values);
+            let outcome = match result {
+                Ok(true) => "pass",
+                Ok(false) => "fail",
+                Err(_) => "error",
             };
+            let mut flag = QCFlag::new();
+            if !result.unwrap_or(false) {
+                flag.set_bit(check.level);
+                if check.errorflag {
+                    flag.set_bit(check.level + ERROR_SHIFT);
+                }
+            }
 
-            for (target, data) in arr {
-                if let DataType::Datetime(_) = data {
+            for record in self.pending[start..].iter_mut() {
+                if record.datetime != datetime
+                    || record.flag.contains(QCFlag::Late)
+                    || !check.flagged().contains(&record.parameter)
+                {
                     continue;
                 }
-                self.append(target, datetime, data);
+                record.flag |= flag;
+                let outcomes = record.outcomes.get_or_insert_with(String::new);
+                if !outcomes.is_empty() {
+                    outcomes.push(';');
+                }
+                outcomes.push_str(&format!("{}:{}={outcome}", check.level, check.name));
+                if let Some(work) = self.map.get_mut(&(record.station.clone(), record.parameter.clone())) {
+                    work.flag |= flag;
+                }
             }
         }
     }
 
+    /// Last value of every parameter of the station set by `set_station`.
     pub fn get_report(&self) -> HashMap<String, (NaiveDateTime, DataType, QCFlag)> {
         let mut map = HashMap::new();
-        for (key, val) in &self.map {
+        for ((station, key), val) in &self.map {
+            if *station != self.station {
+                continue;
+            }
             if let Some(data) = &val.data {
-                map.insert(key.to_string(), (data.0.clone(), data.1.clone(), val.flag));
+                map.insert(key.to_string(), (data.0, data.1.clone(), val.flag));
             }
         }
 
@@ -286,37 +1298,162 @@ impl QCworker {
         println!("{:#?}", self.get_report());
     }
 
-    pub fn save(&self) -> sqlite::Result<()> {
-        if let Some(root) = &self.database {
-            for (key, val) in self.get_report() {
-                let db_path = format!("{}/{}.db", root, val.0.format("%Y%m%d"));
-                let path = Path::new(&db_path);
-                let conn = db_get(path)?;
-                
-                let datetime = val.0.format("'%Y-%m-%d %H:%M:%S'").to_string();
-                let parameter = format!("'{key}'");
-                let flag = val.2.bits();
-                match val.1 {
-                    DataType::Datetime(_) => {}
-                    DataType::Integer(v) => {
-                        let query = format!("INSERT INTO IntegerTable (datetime, parameter, value, flag) VALUES ({datetime}, {parameter}, {v}, {flag});");
-                        conn.execute(query)?;
-                    }
-                    DataType::Float(v) => {
-                        let query = format!("INSERT INTO FloatTable (datetime, parameter, value, flag) VALUES ({datetime}, {parameter}, {v}, {flag});");
-                        conn.execute(query)?;
-                    }
-                    DataType::String(v) => {
-                        let value = format!("'{v}'");
-                        let query = format!("INSERT INTO TextrTable (datetime, parameter, value, flag) VALUES ({datetime}, {parameter}, {value}, {flag});");
-                        conn.execute(query)?;
-                    }
-                    DataType::NULL => {}
-                }
-            }
+    /// Write the results appended since the last save. They are dropped when no database is set.
+    pub fn save(&mut self) -> Result<(), ERROR> {
+        let pending = std::mem::take(&mut self.pending);
+        if let Some(store) = self.database.as_mut() {
+            store.write(&pending)?;
+        }
+        Ok(())
+    }
+
+    /// Change the flag of stored rows by hand, see `Store::override_flags`.
+    pub fn override_flags(&mut self, filter: &QueryFilter, change: &FlagOverride) -> Result<usize, ERROR> {
+        self.save()?;
+        self.database
+            .as_mut()
+            .ok_or("No database set")?
+            .override_flags(filter, change)
+    }
+
+    /// Check the values held back, save, then make the store write out what it still buffers.
+    pub fn flush(&mut self) -> Result<(), ERROR> {
+        self.release(None, None);
+        self.save()?;
+        if let Some(store) = self.database.as_mut() {
+            store.flush()?;
         }
         Ok(())
     }
+
+    /// See `reprocess`, `lines` in time order.
+    fn reprocess_lines(&mut self, parameter: &str, lines: Vec<ReprocessLine>) -> Vec<Record> {
+        for line in lines {
+            self.set_station(line.station);
+            let formation = line.formation.as_deref();
+            if let Err(e) = self.check_line(formation, line.datetime, line.values, line.raw, line.raw_id) {
+                eprintln!("Line of {} not reprocessed: {e}", line.datetime);
+            }
+        }
+        self.release(None, None);
+        let mut result = std::mem::take(&mut self.pending);
+        result.retain(|record| record.parameter == parameter);
+        for record in result.iter_mut() {
+            // gap records have no line
+            if matches!(record.value, DataType::NULL) {
+                record.raw = None;
+                record.raw_id = None;
+            }
+        }
+        result
+    }
+
+    /// An archived line parsed and derived again like `handler` does, with the station it was
+    /// sent for. None when it doesn't parse or isn't of the station and time range of `filter`.
+    pub fn archived_line(&mut self, raw: &RawLine, filter: &QueryFilter) -> Option<ReprocessLine> {
+        if filter.station.is_some() && raw.station != filter.station {
+            return None;
+        }
+        let mut values = self.data_parse(&raw.line).ok()?;
+        let datetime = values
+            .iter()
+            .find_map(|(_, v)| match v {
+                DataType::Datetime(dt) => Some(*dt),
+                _ => None,
+            })
+            // stores keep whole seconds
+            .unwrap_or_else(|| raw.received.with_nanosecond(0).unwrap());
+        if datetime < filter.start || datetime > filter.end {
+            return None;
+        }
+        self.derive(raw.formation.as_deref(), &datetime, &mut values).ok()?;
+        Some(ReprocessLine {
+            datetime,
+            station: raw.station.clone(),
+            formation: raw.formation.clone(),
+            values,
+            raw: Some(raw.line.to_string()),
+            raw_id: Some(raw.id.to_string()),
+        })
+    }
+}
+
+/// A line to check again with `reprocess`.
+#[derive(Debug, Clone)]
+pub struct ReprocessLine {
+    pub datetime: NaiveDateTime,
+    pub station: Option<String>,
+    pub formation: Option<String>,
+    pub values: Vec<(String, DataType)>,
+    pub raw: Option<String>,
+    pub raw_id: Option<String>,
+}
+
+/// Lines put together from stored values of every parameter, one per datetime and station,
+/// with the raw line of the value of `parameter`. Gap records are left out, they are made again.
+pub fn stored_lines(parameter: &str, records: Vec<Record>) -> Vec<ReprocessLine> {
+    let mut lines: BTreeMap<(NaiveDateTime, Option<String>), ReprocessLine> = BTreeMap::new();
+    for record in records {
+        if matches!(record.value, DataType::NULL) {
+            continue;
+        }
+        let line = lines
+            .entry((record.datetime, record.station.clone()))
+            .or_insert_with(|| ReprocessLine {
+                datetime: record.datetime,
+                station: record.station.clone(),
+                formation: None,
+                values: Vec::new(),
+                raw: None,
+                raw_id: None,
+            });
+        if record.parameter == parameter {
+            line.formation = record
+                .raw
+                .as_deref()
+                .filter(|raw| raw.starts_with('F'))
+                .map(|raw| raw.split(',').next().unwrap().to_string());
+            line.raw = record.raw;
+            line.raw_id = record.raw_id;
+        }
+        line.values.push((record.parameter, record.value));
+    }
+    lines.into_values().collect()
+}
+
+/// Check `lines` again with a fresh worker and the current configs, in time order, the way
+/// `handler` checks received lines: every value sees the others of its line and the record
+/// checks run. Values in `maintenance` are flagged. The running workers keep their state.
+/// Returns the records of `parameter` with their new flag, outcomes and config, and the gap
+/// records found. Lines which fail are skipped.
+pub fn reprocess(
+    parameter: &str,
+    mut lines: Vec<ReprocessLine>,
+    maintenance: &[MaintenanceWindow],
+) -> Result<Vec<Record>, ERROR> {
+    QCConfig::load(&config_path(parameter))?;
+    let mut qc = QCworker::new(HashMap::new());
+    qc.set_config_maintenance(maintenance.to_vec());
+    lines.sort_by_key(|line| line.datetime);
+    Ok(qc.reprocess_lines(parameter, lines))
+}
+
+/// Parameters with a config file in `CONFIG_ROOT`.
+pub fn configured_parameters() -> Result<Vec<String>, ERROR> {
+    let mut res = Vec::new();
+    for entry in std::fs::read_dir(CONFIG_ROOT)? {
+        let path = entry?.path();
+        if path.extension().is_none_or(|ext| ext != "toml") {
+            continue;
+        }
+        if let Some(stem) = path.file_stem().and_then(|v| v.to_str()) {
+            if !NON_PARAMETER_CONFIG.contains(&stem) {
+                res.push(stem.to_string());
+            }
+        }
+    }
+    res.sort();
+    Ok(res)
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -342,9 +1479,14 @@ pub fn get_formations_table(conf: &FormationTable, target: &str) -> Option<Vec<S
 
 #[cfg(test)]
 mod test {
-    use crate::lib::data_parser::{data_parser_format, data_parser_key_value};
-
     use super::*;
+    use crate::lib::storage::temp_root;
+
+    /// The record of `parameter` in the result of `handler`.
+    fn record<'a>(result: &'a [Record], parameter: &str) -> &'a Record {
+        result.iter().find(|record| record.parameter == parameter).unwrap()
+    }
+
     #[test]
     fn case1() {
         let path = "./config/formation_table.toml";
@@ -355,11 +1497,559 @@ mod test {
     }
 
     #[test]
-    fn case2() {
+    fn handler_state_and_reload() {
+        let mut qc = QCworker::new(HashMap::new());
+        qc.handler("humidity=50.0").unwrap();
+        assert!(qc.handler("unknown=1.0").is_err());
+        // all or nothing of a line
+        assert!(qc.handler("humidity=51.0,unknown=1.0").is_err());
+        assert_eq!(qc.pending.len(), 1);
+        assert_eq!(qc.parameters(), vec!["humidity".to_string()]);
+
+        let state = qc.state(None, "humidity").unwrap();
+        assert_eq!(state.status, "Running");
+        assert_eq!(state.data.unwrap().1.to_string(), "50");
+
+        assert_eq!(qc.reload_config(None).unwrap(), vec!["humidity".to_string()]);
+        assert!(qc.reload_config(Some("unknown")).is_err());
+        assert!(configured_parameters().unwrap().contains(&"temperature".to_string()));
+
+        assert_eq!(qc.reset(None, Some("humidity")), vec!["humidity".to_string()]);
+        assert!(qc.state(None, "humidity").is_none());
+    }
+
+    #[test]
+    fn failed_line_resent() {
+        let datetime = crate::lib::data_parser::parse_datetime("2023-01-02T00:00:00").unwrap();
+        let at = |seconds: i64| datetime + chrono::Duration::seconds(seconds);
+        let value = |v: f64| ("humidity".to_string(), DataType::Float(v));
+        let mut qc = QCworker::new(HashMap::new());
+        qc.check_line(None, at(0), vec![value(50.0)], None, None).unwrap();
+
+        let line = vec![value(51.0), ("unknown".to_string(), DataType::Float(1.0))];
+        for _ in 0..2 {
+            assert!(qc.check_line(None, at(10), line.clone(), None, None).is_err());
+        }
+        assert_eq!(qc.pending.len(), 1);
+        assert_eq!(qc.state(None, "humidity").unwrap().data.unwrap().1.to_string(), "50");
+        assert!(qc.state(None, "unknown").is_none());
+
+        // neither dropped as a duplicate nor late after the failed attempts
+        qc.check_line(None, at(10), vec![value(51.0)], None, None).unwrap();
+        assert_eq!(qc.pending.len(), 2);
+        assert!(!qc.pending[1].flag.contains(QCFlag::Late));
+    }
+
+    #[test]
+    fn state_file_written() {
+        let path = std::env::temp_dir().join("qc_worker_case4/state.toml");
+        let mut qc = QCworker::new(HashMap::new());
+        qc.handler("humidity=50.0").unwrap();
+        qc.save_state(&path).unwrap();
+
+        let state: StateFile = toml::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(state.workers.len(), 1);
+        assert_eq!(state.workers[0].parameter, "humidity");
+        assert_eq!(state.workers[0].value, Some(toml::Value::Float(50.0)));
+    }
+
+    #[test]
+    fn lines_archived() {
+        let root = &temp_root("qc_worker_case5");
+
+        let mut qc = QCworker::new(HashMap::new());
+        qc.set_archive(RawArchive::new(root, 0));
+        qc.set_source(Some("[::1]:5000".to_string()));
+        qc.handler("humidity=50.0").unwrap();
+        assert!(qc.handler("F99,2023-01-02T00:00:00,1").is_err());
+        let raw_id = qc.pending[0].raw_id.clone().unwrap();
+
+        let archive = RawArchive::new(root, 0);
+        let now = chrono::offset::Local::now().naive_local();
+        let mut lines = Vec::new();
+        archive
+            .query(now - chrono::Duration::hours(1), now, &mut |raw| {
+                lines.push(raw);
+                true
+            })
+            .unwrap();
+        // lines which fail to parse are kept too
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0].id, raw_id);
+        assert_eq!(lines[1].formation.as_deref(), Some("F99"));
+    }
+
+    #[test]
+    fn reprocess_archived_lines() {
+        let datetime = crate::lib::data_parser::parse_datetime("2023-01-02T00:00:00").unwrap();
+        let lines = [
+            RawLine {
+                id: "1".to_string(),
+                received: datetime,
+                source: None,
+                formation: None,
+                line: "humidity=50.0".to_string(),
+                station: Some("st1".to_string()),
+            },
+            RawLine {
+                id: "2".to_string(),
+                received: datetime,
+                source: None,
+                formation: Some("F99".to_string()),
+                line: "F99,2023-01-01T00:00:00,1".to_string(),
+                station: Some("st1".to_string()),
+            },
+            RawLine {
+                id: "3".to_string(),
+                received: datetime,
+                source: None,
+                formation: None,
+                line: "humidity=60.0".to_string(),
+                station: Some("st2".to_string()),
+            },
+            RawLine {
+                id: "4".to_string(),
+                received: datetime + chrono::Duration::days(1),
+                source: None,
+                formation: None,
+                line: "humidity=70.0".to_string(),
+                station: Some("st1".to_string()),
+            },
+        ];
+        let filter = QueryFilter { station: Some("st1".to_string()), ..QueryFilter::between(datetime, datetime) };
+        let mut qc = QCworker::new(HashMap::new());
+        let lines = lines.iter().filter_map(|raw| qc.archived_line(raw, &filter)).collect::<Vec<_>>();
+        assert_eq!(lines.len(), 1);
+        assert_eq!(lines[0].raw_id.as_deref(), Some("1"));
+        assert_eq!(lines[0].station.as_deref(), Some("st1"));
+
+        let records = reprocess("humidity", lines, &[]).unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].flag.bits(), QCFlag::Clear.bits());
+        assert_eq!(records[0].station.as_deref(), Some("st1"));
+        assert_eq!(records[0].raw_id.as_deref(), Some("1"));
+        assert!(records[0].config_hash.is_some());
+        assert!(reprocess("unknown", Vec::new(), &[]).is_err());
+    }
+
+    #[test]
+    fn flag_display_and_parse() {
+        let flag = QCFlag::L0_Warn | QCFlag::L1_Warn | QCFlag::L1_Error;
+        assert_eq!(flag.to_string(), "L0_Warn | L1_Warn | L1_Error");
+        assert_eq!(QCFlag::new().to_string(), "Clear");
+        assert_eq!("L0_Warn | L1_Warn | L1_Error".parse::<QCFlag>().unwrap().bits(), flag.bits());
+        assert_eq!(flag.bits().to_string().parse::<QCFlag>().unwrap().bits(), flag.bits());
+        assert!("L99_Warn".parse::<QCFlag>().is_err());
+
+        let config = QCConfig::load(&config_path("humidity")).unwrap();
+        let terms = QCFlag::L1_Warn.explain(Some(&config));
+        assert_eq!(terms.len(), 1);
+        assert_eq!(terms[0].level, Some(1));
+
+        #[derive(Serialize, Deserialize)]
+        struct Row {
+            flag: QCFlag,
+        }
+        let text = toml::to_string(&Row { flag }).unwrap();
+        assert_eq!(text.trim(), "flag = \"L0_Warn | L1_Warn | L1_Error\"");
+        let row: Row = toml::from_str(&text).unwrap();
+        assert_eq!(row.flag.bits(), flag.bits());
+        let row: Row = toml::from_str("flag = 3").unwrap();
+        assert_eq!(row.flag.bits(), 3);
+    }
+
+    #[test]
+    fn stop_and_start() {
+        let mut qc = QCworker::new(HashMap::new());
+        qc.stop(None, Some("humidity")).unwrap();
+        assert_eq!(qc.state(None, "humidity").unwrap().status, "Stop");
+        let result = qc.handler("humidity=50.0").unwrap();
+        assert!(record(&result, "humidity").flag.contains(QCFlag::Maintenance));
+        assert_eq!(qc.pending[0].outcomes.as_deref(), Some(""));
+
+        assert_eq!(qc.start(None, None).unwrap(), vec!["humidity".to_string()]);
+        let result = qc.handler("humidity=51.0").unwrap();
+        assert!(!record(&result, "humidity").flag.contains(QCFlag::Maintenance));
+        assert_eq!(qc.state(None, "humidity").unwrap().status, "Running");
+        assert!(qc.stop(None, Some("unknown")).is_err());
+    }
+
+    #[test]
+    fn maintenance_and_override() {
+        let mut qc = QCworker::new(HashMap::new());
+        qc.set_station(Some("st1".to_string()));
+        qc.add_maintenance(
+            MaintenanceWindow::new(
+                "2023-01-02T08:00:00",
+                "2023-01-02T10:00:00",
+                Some("st1".to_string()),
+                Vec::new(),
+                "calibration".to_string(),
+            )
+            .unwrap(),
+        );
+        let datetime = crate::lib::data_parser::parse_datetime("2023-01-02T09:00:00").unwrap();
+        qc.append("humidity", datetime, DataType::Float(50.0)).unwrap();
+        qc.append("humidity", datetime + chrono::Duration::hours(2), DataType::Float(50.0))
+            .unwrap();
+        assert!(qc.pending[0].flag.contains(QCFlag::Maintenance));
+        assert!(!qc.pending[1].flag.contains(QCFlag::Maintenance));
+        // the worker keeps running
+        assert_eq!(qc.state(Some("st1"), "humidity").unwrap().status, "Running");
+
+        let filter = QueryFilter::between(datetime, datetime);
+        let change = FlagOverride {
+            set: QCFlag::L0_Error,
+            clear: QCFlag::new(),
+            user: "tech".to_string(),
+            reason: "test".to_string(),
+        };
+        assert!(qc.override_flags(&filter, &change).is_err());
+    }
+
+    #[test]
+    fn state_restored() {
+        let path = std::env::temp_dir().join("qc_worker_case10/state.toml");
+        let mut qc = QCworker::new(HashMap::new());
+        qc.handler("humidity=50.0").unwrap();
+        qc.stop(None, Some("humidity")).unwrap();
+        let window = MaintenanceWindow::new("2023-01-02", "2023-01-03", None, Vec::new(), String::new())
+            .unwrap();
+        let added = MaintenanceWindow::new("2023-01-04", "2023-01-05", None, Vec::new(), String::new())
+            .unwrap();
+        qc.set_config_maintenance(vec![window.clone()]);
+        qc.add_maintenance(added.clone());
+        qc.save_state(&path).unwrap();
+
+        // the window was removed from the config meanwhile
+        let mut restored = QCworker::new(HashMap::new());
+        restored.set_config_maintenance(Vec::new());
+        assert_eq!(restored.restore_state(&path, 60).unwrap(), vec!["humidity".to_string()]);
+        let state = restored.state(None, "humidity").unwrap();
+        assert_eq!(state.status, "Stop");
+        assert_eq!(state.data.unwrap().1.to_string(), "50");
+        assert_eq!(restored.maintenance(), &[added]);
+        assert!(restored.remove_maintenance(1).is_err());
+        restored.remove_maintenance(0).unwrap();
+        assert!(restored.maintenance().is_empty());
+        // a window of the config is not saved, also after removing one before it
+        qc.remove_maintenance(0).unwrap();
+        qc.save_state(&path).unwrap();
+        let mut restored = QCworker::new(HashMap::new());
+        restored.restore_state(&path, 60).unwrap();
+        assert_eq!(restored.maintenance().len(), 1);
+
+        // too old
+        let mut state: StateFile = toml::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        state.saved = "2023-01-02T00:00:00".to_string();
+        std::fs::write(&path, toml::to_string(&state).unwrap()).unwrap();
+        let mut restored = QCworker::new(HashMap::new());
+        assert!(restored.restore_state(&path, 60).unwrap().is_empty());
+        assert_eq!(restored.restore_state(&path, 0).unwrap().len(), 1);
+        assert!(restored.restore_state("missing.toml", 0).unwrap().is_empty());
+    }
+
+    #[test]
+    fn duplicates_late_and_reordering() {
+        let datetime = crate::lib::data_parser::parse_datetime("2023-01-02T09:00:00").unwrap();
+        let at = |seconds: i64| datetime + chrono::Duration::seconds(seconds);
+        let mut qc = QCworker::new(HashMap::new());
+        qc.append("humidity", at(0), DataType::Float(50.0)).unwrap();
+        // duplicate, dropped
+        qc.append("humidity", at(0), DataType::Float(51.0)).unwrap();
+        // late, stored without running the checks
+        qc.append("humidity", at(-10), DataType::Float(52.0)).unwrap();
+        qc.append("humidity", at(10), DataType::Float(53.0)).unwrap();
+        assert_eq!(qc.pending.len(), 3);
+        assert_eq!(qc.pending[1].flag.bits(), QCFlag::Late.bits());
+        assert!(!qc.pending[2].flag.contains(QCFlag::Late));
+        let state = qc.state(None, "humidity").unwrap();
+        assert_eq!((state.duplicates, state.rejected), (1, 0));
+        assert_eq!(state.data.unwrap().0, at(10));
+        assert_eq!("Late | L0_Warn".parse::<QCFlag>().unwrap().to_string(), "L0_Warn | Late");
+
+        let path = std::env::temp_dir().join("qc_worker_case11.toml");
+        let config = std::fs::read_to_string("./config/humidity.toml").unwrap();
+        let config = config.replace("[Global]", "[Global]\nordering = \"buffer\"\nreorder_window = 30");
+        std::fs::write(&path, config).unwrap();
+        let mut inner = WorkerInner::new("humidity").unwrap();
+        inner.config = QCConfig::load(path.to_str().unwrap()).unwrap();
+        assert!(inner.receive(at(10), DataType::Float(50.0), &[]).is_empty());
+        assert!(inner.receive(at(0), DataType::Float(50.0), &[]).is_empty());
+        let checked = inner.receive(at(35), DataType::Float(50.0), &[]);
+        assert_eq!(checked.iter().map(|v| v.datetime).collect::<Vec<_>>(), vec![at(0)]);
+        // older than a checked value
+        assert_eq!(inner.receive(at(-5), DataType::Float(50.0), &[])[0].flag.bits(), QCFlag::Late.bits());
+        assert!(inner.receive(at(35), DataType::Float(50.0), &[]).is_empty());
+        assert_eq!(inner.duplicates, 1);
+        let checked = inner.release();
+        assert_eq!(checked.iter().map(|v| v.datetime).collect::<Vec<_>>(), vec![at(10), at(35)]);
+    }
+
+    #[test]
+    fn gap_records() {
+        let datetime = crate::lib::data_parser::parse_datetime("2023-01-02T09:00:00").unwrap();
+        let at = |seconds: i64| datetime + chrono::Duration::seconds(seconds);
+        let path = std::env::temp_dir().join("qc_worker_case12.toml");
+        let config = std::fs::read_to_string("./config/humidity.toml").unwrap();
+        std::fs::write(&path, config.replace("[Global]", "[Global]\ninterval = 60")).unwrap();
+        let mut qc = QCworker::new(HashMap::new());
+        qc.append("humidity", at(0), DataType::Float(50.0)).unwrap();
+        qc.map.get_mut(&(None, "humidity".to_string())).unwrap().config = QCConfig::load(path.to_str().unwrap()).unwrap();
+
+        // within interval and tolerance
+        qc.append("humidity", at(85), DataType::Float(50.0)).unwrap();
+        assert!(!qc.pending[1].flag.contains(QCFlag::Gap));
+        // found by the next value
+        qc.append("humidity", at(200), DataType::Float(50.0)).unwrap();
+        assert!(matches!(qc.pending[2].value, DataType::NULL));
+        assert_eq!((qc.pending[2].datetime, qc.pending[2].flag.bits()), (at(145), QCFlag::Gap.bits()));
+        assert!(qc.pending[3].flag.contains(QCFlag::Gap));
+
+        // found by the timer, once
+        let received = qc.map[&(None, "humidity".to_string())].received.unwrap();
+        assert!(qc.check_gaps(received + chrono::Duration::seconds(90)).is_empty());
+        let gaps = qc.check_gaps(received + chrono::Duration::seconds(91));
+        assert_eq!((gaps.len(), gaps[0].datetime), (1, at(260)));
+        assert!(qc.check_gaps(received + chrono::Duration::seconds(200)).is_empty());
+        qc.append("humidity", at(600), DataType::Float(50.0)).unwrap();
+        assert_eq!(qc.pending.len(), 6);
+        assert!(qc.pending[5].flag.contains(QCFlag::Gap));
+
+        // the gap and the value of a line are both returned, in order
+        let result = qc.handler("F1,2023-01-02T09:12:00,25.0,50.0").unwrap();
+        let humidity = result.iter().filter(|record| record.parameter == "humidity").collect::<Vec<_>>();
+        assert_eq!(humidity.len(), 2);
+        assert!(matches!(humidity[0].value, DataType::NULL));
+        assert_eq!(humidity[1].datetime, at(720));
+    }
+
+    #[test]
+    fn record_checks_flag_values() {
+        let mut qc = QCworker::new(HashMap::new());
+        qc.record_checks = Some(
+            toml::from_str(
+                r#"
+                [[check]]
+                name = "humidity below temperature"
+                parameters = ["humidity", "temperature"]
+                flag = ["humidity"]
+                module_type = "python"
+                path = "./module/python/less_equal.py"
+                level = 1
+                errorflag = true
+                "#,
+            )
+            .unwrap(),
+        );
+        let result = qc.handler("F1,2023-01-02T00:00:00,10.0,50.0").unwrap();
+        assert!(record(&result, "humidity").flag.contains(QCFlag::L1_Warn | QCFlag::L1_Error));
+        assert!(!record(&result, "temperature").flag.contains(QCFlag::L1_Warn));
+        let humidity = qc.pending.iter().find(|v| v.parameter == "humidity").unwrap();
+        assert!(humidity
+            .outcomes
+            .as_deref()
+            .unwrap()
+            .ends_with("1:humidity below temperature=fail"));
+        assert!(qc.state(None, "humidity").unwrap().flag.contains(QCFlag::L1_Error));
+
+        // skipped without temperature
+        let result = qc.handler("humidity=5.0").unwrap();
+        assert!(!record(&result, "humidity").flag.contains(QCFlag::L1_Warn));
+    }
+
+    #[test]
+    fn derived_parameters_checked() {
+        let path = std::env::temp_dir().join("qc_worker_case14.toml");
+        std::fs::write(&path, "[[derived]]\nname = \"temperature\"\nexpr = \"humidity / 2\"").unwrap();
+        let mut qc = QCworker::new(HashMap::new());
+        qc.derived = Some(DerivedConfig::load(path.to_str().unwrap()).unwrap());
+        let result = qc.handler("humidity=50.0").unwrap();
+        assert_eq!(record(&result, "temperature").value.to_string(), "25");
+        assert_eq!(qc.parameters(), vec!["humidity".to_string(), "temperature".to_string()]);
+    }
+
+    #[test]
+    fn expr_checks_with_fields() {
+        let path = std::env::temp_dir().join("qc_worker_case15.toml");
+        std::fs::write(
+            &path,
+            r#"
+            [Global]
+            max_level = 0
+
+            [[level_0.module]]
+            name = "above temperature"
+            module_type = "expr"
+            expr = "value > temperature && elapsed < 60"
+            "#,
+        )
+        .unwrap();
+        let mut qc = QCworker::new(HashMap::new());
+        qc.handler("F1,2023-01-02T00:00:00,10.0,50.0").unwrap();
+        qc.map.get_mut(&(None, "humidity".to_string())).unwrap().config = QCConfig::load(path.to_str().unwrap()).unwrap();
+
+        let result = qc.handler("F1,2023-01-02T00:00:10,10.0,50.0").unwrap();
+        assert!(!record(&result, "humidity").flag.contains(QCFlag::L0_Warn));
+        let result = qc.handler("F1,2023-01-02T00:00:20,10.0,5.0").unwrap();
+        assert!(record(&result, "humidity").flag.contains(QCFlag::L0_Warn));
+        let humidity = qc.pending.iter().rev().find(|v| v.parameter == "humidity").unwrap();
+        assert!(humidity.outcomes.as_deref().unwrap().contains("above temperature=fail"));
+
+        std::fs::write(&path, "[Global]\nmax_level = 0\n[[level_0.module]]\nname = \"x\"\nmodule_type = \"expr\"\nexpr = \"(value\"").unwrap();
+        assert!(QCConfig::load(path.to_str().unwrap()).is_err());
+    }
+
+    #[test]
+    fn module_errorflag_sets_error_bit() {
+        let path = std::env::temp_dir().join("qc_worker_module_errorflag.toml");
+        std::fs::write(
+            &path,
+            "[Global]\nmax_level = 0\n[[level_0.module]]\nname = \"range\"\nmodule_type = \"expr\"\n\
+             expr = \"value <= 100\"\nerrorflag = true",
+        )
+        .unwrap();
+        let mut qc = QCworker::new(HashMap::new());
+        qc.handler("humidity=50.0").unwrap();
+        qc.map.get_mut(&(None, "humidity".to_string())).unwrap().config = QCConfig::load(path.to_str().unwrap()).unwrap();
+
+        let result = qc.handler("humidity=150.0").unwrap();
+        assert!(record(&result, "humidity").flag.contains(QCFlag::L0_Warn | QCFlag::L0_Error));
+    }
+
+    #[test]
+    fn stations_keep_separate_state() {
         let mut qc = QCworker::new(HashMap::new());
-        qc.run();
+        let datetime = crate::lib::data_parser::parse_datetime("2023-01-02T00:00:00").unwrap();
+        // same timestamps, and st2 behind st1, on one parameter
+        for (station, seconds, value) in [("st1", 0, 50.0), ("st2", 0, 60.0), ("st1", 10, 51.0), ("st2", 5, 61.0)] {
+            qc.set_station(Some(station.to_string()));
+            qc.append("humidity", datetime + chrono::Duration::seconds(seconds), DataType::Float(value))
+                .unwrap();
+        }
+        assert_eq!(qc.pending.len(), 4);
+        assert_eq!(qc.parameters(), vec!["humidity".to_string()]);
 
-        qc.show("temperature");
-        qc.show("humidity");
+        let states = qc.states(None, Some("humidity"));
+        assert_eq!(states.len(), 2);
+        for state in &states {
+            assert_eq!(state.rejected, 0);
+            assert_eq!(state.duplicates, 0);
+        }
+        let st2 = qc.state(Some("st2"), "humidity").unwrap();
+        assert_eq!(st2.data.unwrap().1.to_string(), "61");
+        assert!(qc.state(None, "humidity").is_none());
+        assert_eq!(qc.get_report()["humidity"].1.to_string(), "61");
+
+        assert_eq!(qc.reset(Some("st1"), None), vec!["humidity@st1".to_string()]);
+        assert!(qc.state(Some("st1"), "humidity").is_none());
+        assert!(qc.state(Some("st2"), "humidity").is_some());
+    }
+
+    #[test]
+    fn duplicates_and_late_values_per_station() {
+        let datetime = crate::lib::data_parser::parse_datetime("2023-01-02T09:00:00").unwrap();
+        let at = |seconds: i64| datetime + chrono::Duration::seconds(seconds);
+        let mut qc = QCworker::new(HashMap::new());
+        qc.set_station(Some("st1".to_string()));
+        qc.append("humidity", at(10), DataType::Float(50.0)).unwrap();
+        // same timestamp and an older one from another station
+        qc.set_station(Some("st2".to_string()));
+        qc.append("humidity", at(10), DataType::Float(51.0)).unwrap();
+        qc.append("humidity", at(20), DataType::Float(52.0)).unwrap();
+        qc.set_station(Some("st1".to_string()));
+        qc.append("humidity", at(15), DataType::Float(53.0)).unwrap();
+        assert_eq!(qc.pending.len(), 4);
+        assert!(qc.pending.iter().all(|v| !v.flag.contains(QCFlag::Late)));
+
+        // still dropped within one station
+        qc.append("humidity", at(15), DataType::Float(54.0)).unwrap();
+        assert_eq!(qc.pending.len(), 4);
+        assert_eq!(qc.state(Some("st1"), "humidity").unwrap().duplicates, 1);
+        assert_eq!(qc.state(Some("st2"), "humidity").unwrap().duplicates, 0);
+    }
+
+    #[test]
+    fn module_state_restored() {
+        let path = std::env::temp_dir().join("qc_worker_module_state.toml");
+        std::fs::write(
+            &path,
+            "[Global]\nmax_level = 0\n[[level_0.module]]\nname = \"step\"\nmodule_type = \"expr\"\nexpr = \"math::abs(value - previous) <= 5\"",
+        )
+        .unwrap();
+        let datetime = crate::lib::data_parser::parse_datetime("2023-01-02T00:00:00").unwrap();
+        let worker = || {
+            let mut inner = WorkerInner::new("humidity").unwrap();
+            inner.config = QCConfig::load(path.to_str().unwrap()).unwrap();
+            inner
+        };
+        let mut inner = worker();
+        inner.qc_handle(datetime, DataType::Float(50.0), &[]);
+        let saved = inner.save_modules();
+        assert_eq!(saved.len(), 1);
+        let state = StateFile {
+            workers: vec![WorkerSnapshot {
+                parameter: "humidity".to_string(),
+                station: None,
+                status: "Running".to_string(),
+                datetime: None,
+                value: None,
+                flag: 0,
+                samples: 0,
+                modules: saved,
+            }],
+            ..Default::default()
+        };
+        let state: StateFile = toml::from_str(&toml::to_string(&state).unwrap()).unwrap();
+        let saved = state.workers[0].modules.clone();
+
+        let mut restored = worker();
+        assert!(restored.restore_modules(&saved));
+        restored.status = QCStatus::Running;
+        restored.qc_handle(datetime + chrono::Duration::seconds(10), DataType::Float(70.0), &[]);
+        // compared with the value before the restart
+        assert!(restored.flag.contains(QCFlag::L0_Warn));
+        assert!(!worker().restore_modules(&[]));
+    }
+
+    #[test]
+    fn reprocess_checks_whole_lines() {
+        let path = std::env::temp_dir().join("qc_worker_reprocess_record.toml");
+        std::fs::write(
+            &path,
+            "[[check]]\nname = \"drier\"\nparameters = [\"humidity\", \"temperature\"]\nflag = [\"humidity\"]\nmodule_type = \"expr\"\nexpr = \"humidity < temperature\"\nlevel = 1",
+        )
+        .unwrap();
+        let datetime = crate::lib::data_parser::parse_datetime("2023-01-02T00:00:00").unwrap();
+        let record = |parameter: &str, seconds: i64, value: f64| Record {
+            datetime: datetime + chrono::Duration::seconds(seconds),
+            parameter: parameter.to_string(),
+            station: Some("st1".to_string()),
+            value: DataType::Float(value),
+            raw: Some(format!("{parameter} line")),
+            ..Default::default()
+        };
+        let stored = vec![
+            record("humidity", 0, 50.0),
+            record("temperature", 0, 60.0),
+            record("humidity", 10, 50.0),
+            record("temperature", 10, 20.0),
+            // nothing to compare with
+            record("humidity", 20, 50.0),
+        ];
+        let lines = stored_lines("humidity", stored);
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[1].values.len(), 2);
+        assert_eq!(lines[1].raw.as_deref(), Some("humidity line"));
+
+        let mut qc = QCworker::new(HashMap::new());
+        qc.record_checks = Some(RecordConfig::load(path.to_str().unwrap()).unwrap());
+        let records = qc.reprocess_lines("humidity", lines);
+        assert_eq!(records.len(), 3);
+        assert!(records.iter().all(|v| v.parameter == "humidity"));
+        assert!(!records[0].flag.contains(QCFlag::L1_Warn));
+        assert!(records[1].flag.contains(QCFlag::L1_Warn));
+        assert!(records[1].outcomes.as_deref().unwrap().contains("1:drier=fail"));
+        assert!(!records[2].flag.contains(QCFlag::L1_Warn));
     }
 }
@@ -0,0 +1,189 @@
+use std::fmt::Debug;
+
+use chrono::NaiveDateTime;
+use serde_derive::Deserialize;
+
+use super::{
+    config_parser::ModuleType,
+    data_parser::DataType,
+    expr::{self, ExprModule},
+    py_module::PythonModule,
+    qc_worker::WARN_LEVELS,
+    RecordModule, ERROR,
+};
+
+pub const RECORD_CONFIG: &str = "config/record.toml";
+
+/// `config/record.toml`, checks over several values of one line. They run after every
+/// parameter of the line is checked.
+#[derive(Debug, Deserialize, Default)]
+pub struct RecordConfig {
+    #[serde(default)]
+    pub check: Vec<RecordCheck>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RecordCheck {
+    pub name: String,
+    /// `F{n}` of the formation table, every line when empty
+    #[serde(default)]
+    pub formations: Vec<String>,
+    /// values given to the module in this order, the check is skipped when one is missing
+    pub parameters: Vec<String>,
+    /// parameters flagged when the check fails, all of `parameters` when empty
+    #[serde(default)]
+    pub flag: Vec<String>,
+    pub module_type: String,
+    /// python module
+    #[serde(default)]
+    pub path: String,
+    /// `module_type = "expr"`, true when the values pass; they are variables by parameter name
+    pub expr: Option<String>,
+    /// the warning bit of this level is set when the check fails
+    #[serde(default)]
+    pub level: usize,
+    /// and the error bit too
+    #[serde(default)]
+    pub errorflag: bool,
+    #[serde(skip)]
+    instance: Option<Box<dyn RecordModule>>,
+}
+
+impl Debug for dyn RecordModule + 'static {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("RecordModule")
+    }
+}
+
+impl RecordConfig {
+    /// No checks when the file does not exist.
+    pub fn load(path: &str) -> Result<Self, ERROR> {
+        if !std::path::Path::new(path).exists() {
+            return Ok(RecordConfig::default());
+        }
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| format!("Can't open config file {path}: {e}"))?;
+        let config: RecordConfig =
+            toml::from_str(&contents).map_err(|e| format!("Invalid config {path}: {e}"))?;
+        for check in &config.check {
+            if check.level >= WARN_LEVELS {
+                return Err(format!(
+                    "level of {} in {path} must be below {WARN_LEVELS}",
+                    check.name
+                )
+                .into());
+            }
+            match (ModuleType::from(&check.module_type), &check.expr) {
+                (ModuleType::Python, _) => {}
+                (ModuleType::Expr, Some(v)) => {
+                    expr::compile(v)?;
+                }
+                _ => {
+                    return Err(format!(
+                        "{} in {path}: record checks support python modules and expr",
+                        check.name
+                    )
+                    .into())
+                }
+            }
+        }
+        Ok(config)
+    }
+}
+
+impl RecordCheck {
+    /// The values of the check when it applies to a line of `formation`.
+    pub fn values(
+        &self,
+        formation: Option<&str>,
+        line: &[(String, DataType)],
+    ) -> Option<Vec<(String, DataType)>> {
+        if !self.formations.is_empty()
+            && !formation.is_some_and(|v| self.formations.iter().any(|f| f == v))
+        {
+            return None;
+        }
+        self.parameters
+            .iter()
+            .map(|parameter| line.iter().find(|(key, _)| key == parameter).cloned())
+            .collect()
+    }
+
+    /// Parameters whose value is flagged when the check fails.
+    pub fn flagged(&self) -> &[String] {
+        if self.flag.is_empty() {
+            &self.parameters
+        } else {
+            &self.flag
+        }
+    }
+
+    /// Run the module, it is loaded on first use.
+    pub fn run(
+        &mut self,
+        datetime: &NaiveDateTime,
+        values: &[(String, DataType)],
+    ) -> Result<bool, ERROR> {
+        if self.instance.is_none() {
+            self.instance = match &self.expr {
+                Some(v) if ModuleType::from(&self.module_type) == ModuleType::Expr => {
+                    Some(Box::new(ExprModule::new(v)?))
+                }
+                _ => Some(Box::new(PythonModule::new(&self.name, self.path.as_str())?)),
+            };
+        }
+        self.instance
+            .as_ref()
+            .unwrap()
+            .run_record(self.level, datetime, values)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn python_and_expr_checks() {
+        let mut config: RecordConfig = toml::from_str(
+            r#"
+            [[check]]
+            name = "dew point below temperature"
+            formations = ["F2"]
+            parameters = ["dewpoint", "temperature"]
+            flag = ["dewpoint"]
+            module_type = "python"
+            path = "./module/python/less_equal.py"
+            "#,
+        )
+        .unwrap();
+        let check = &mut config.check[0];
+        let line = vec![
+            ("temperature".to_string(), DataType::Float(10.0)),
+            ("dewpoint".to_string(), DataType::Float(12.0)),
+        ];
+        assert!(check.values(Some("F1"), &line).is_none());
+        assert!(check.values(Some("F2"), &line[..1]).is_none());
+        let values = check.values(Some("F2"), &line).unwrap();
+        assert_eq!(values[0].0, "dewpoint");
+        assert_eq!(check.flagged(), ["dewpoint".to_string()]);
+
+        let datetime =
+            NaiveDateTime::parse_from_str("2023-01-02T00:00:00", "%Y-%m-%dT%H:%M:%S").unwrap();
+        assert!(!check.run(&datetime, &values).unwrap());
+        assert!(check.run(&datetime, &line).unwrap());
+
+        let mut config: RecordConfig = toml::from_str(
+            r#"
+            [[check]]
+            name = "dew point below temperature"
+            parameters = ["dewpoint", "temperature"]
+            module_type = "expr"
+            expr = "dewpoint <= temperature"
+            "#,
+        )
+        .unwrap();
+        let check = &mut config.check[0];
+        assert!(!check.run(&datetime, &values).unwrap());
+    }
+}
@@ -0,0 +1,248 @@
+use std::{
+    fs::{File, OpenOptions},
+    path::Path,
+};
+
+use chrono::{Duration, NaiveDateTime};
+use serde_derive::{Deserialize, Serialize};
+
+use crate::lib::ERROR;
+
+// station is last, files written before it have rows without one
+const HEADER: [&str; 6] = ["id", "received", "source", "formation", "line", "station"];
+
+/// One line as it was received, before parsing.
+#[derive(Debug, Clone)]
+pub struct RawLine {
+    /// `{received}-{n}`, stored as `raw_id` with the values parsed from the line
+    pub id: String,
+    pub received: NaiveDateTime,
+    /// peer address of the daemon request
+    pub source: Option<String>,
+    /// `F{n}` of formatted lines
+    pub formation: Option<String>,
+    pub line: String,
+    /// station the line was sent for, its values are stored with it
+    pub station: Option<String>,
+}
+
+fn default_root() -> String {
+    "database/raw".to_string()
+}
+
+/// `[archive]` of the daemon config, no archive is written without it.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ArchiveConfig {
+    #[serde(default = "default_root")]
+    pub root: String,
+    /// days of files kept, 0 keeps everything
+    #[serde(default)]
+    pub keep_days: u64,
+}
+
+impl ArchiveConfig {
+    pub fn open(&self) -> Result<RawArchive, ERROR> {
+        std::fs::create_dir_all(&self.root)
+            .map_err(|e| format!("Can't create archive root {}: {e}", self.root))?;
+        Ok(RawArchive::new(&self.root, self.keep_days))
+    }
+}
+
+/// Received lines in daily csv files `{root}/{YYYYMMDD}.csv` by receive time,
+/// files older than `keep_days` are removed when a new day starts.
+pub struct RawArchive {
+    root: String,
+    keep_days: u64,
+    // file of the current day
+    current: Option<(String, ::csv::Writer<File>)>,
+    sequence: u64,
+}
+
+impl RawArchive {
+    pub fn new<S: AsRef<str>>(root: S, keep_days: u64) -> Self {
+        RawArchive {
+            root: root.as_ref().to_string(),
+            keep_days,
+            current: None,
+            sequence: 0,
+        }
+    }
+
+    fn file_path(&self, date: &chrono::NaiveDate) -> String {
+        format!("{}/{}.csv", self.root, date.format("%Y%m%d"))
+    }
+
+    /// Write `line` before it is processed, returns the id of the archived line.
+    pub fn append(
+        &mut self,
+        received: NaiveDateTime,
+        source: Option<String>,
+        station: Option<String>,
+        line: &str,
+    ) -> Result<String, ERROR> {
+        self.sequence += 1;
+        let raw = RawLine {
+            id: format!("{}-{}", received.format("%Y%m%dT%H%M%S%6f"), self.sequence),
+            received,
+            source,
+            formation: line
+                .strip_prefix('F')
+                .and_then(|v| v.split_once(','))
+                .map(|(n, _)| format!("F{n}")),
+            line: line.to_string(),
+            station,
+        };
+        self.write(&raw)?;
+        Ok(raw.id)
+    }
+
+    pub fn write(&mut self, raw: &RawLine) -> Result<(), ERROR> {
+        let path = self.file_path(&raw.received.date());
+        if self.current.as_ref().is_none_or(|(current, _)| *current != path) {
+            let new_file = !Path::new(&path).exists();
+            let file = OpenOptions::new().create(true).append(true).open(&path)?;
+            let mut writer = ::csv::Writer::from_writer(file);
+            if new_file {
+                writer.write_record(HEADER)?;
+                self.rotate(&raw.received)?;
+            }
+            self.current = Some((path, writer));
+        }
+
+        let (_, writer) = self.current.as_mut().unwrap();
+        writer.write_record([
+            raw.id.as_str(),
+            &raw.received.format("%Y-%m-%d %H:%M:%S%.6f").to_string(),
+            raw.source.as_deref().unwrap_or_default(),
+            raw.formation.as_deref().unwrap_or_default(),
+            raw.line.as_str(),
+            raw.station.as_deref().unwrap_or_default(),
+        ])?;
+        // the line must be on disk before its values
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Remove the files older than `keep_days` before `now`.
+    fn rotate(&self, now: &NaiveDateTime) -> Result<(), ERROR> {
+        if self.keep_days == 0 {
+            return Ok(());
+        }
+        let oldest = self.file_path(&(now.date() - Duration::days(self.keep_days as i64)));
+        for entry in std::fs::read_dir(&self.root)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == "csv")
+                && path.to_str().is_some_and(|v| v < oldest.as_str())
+            {
+                std::fs::remove_file(path)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Lines received between `start` and `end` (inclusive), in receive order.
+    /// `callback` is called for each line, returning false stops the query.
+    pub fn query(
+        &self,
+        start: NaiveDateTime,
+        end: NaiveDateTime,
+        callback: &mut dyn FnMut(RawLine) -> bool,
+    ) -> Result<(), ERROR> {
+        let mut date = start.date();
+        while date <= end.date() {
+            let path = self.file_path(&date);
+            date += Duration::days(1);
+            if !Path::new(&path).exists() {
+                continue;
+            }
+
+            let mut reader = ::csv::ReaderBuilder::new().flexible(true).from_path(&path)?;
+            for row in reader.records() {
+                let row = row?;
+                let field = |i: usize| row.get(i).unwrap_or_default();
+                let received = NaiveDateTime::parse_from_str(field(1), "%Y-%m-%d %H:%M:%S%.f")
+                    .map_err(|e| format!("{path}: {e}"))?;
+                if received < start || received > end {
+                    continue;
+                }
+                let raw = RawLine {
+                    id: field(0).to_string(),
+                    received,
+                    source: Some(field(2).to_string()).filter(|v| !v.is_empty()),
+                    formation: Some(field(3).to_string()).filter(|v| !v.is_empty()),
+                    line: field(4).to_string(),
+                    station: Some(field(5).to_string()).filter(|v| !v.is_empty()),
+                };
+                if !callback(raw) {
+                    return Ok(());
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::lib::{data_parser::parse_datetime, storage::temp_root};
+
+    use super::*;
+
+    #[test]
+    fn append_query_and_keep_days() {
+        let root = &temp_root("qc_archive_case1");
+
+        let mut archive = RawArchive::new(root, 2);
+        let day = parse_datetime("2023-01-01").unwrap();
+        let id = archive
+            .append(
+                day,
+                Some("[::1]:5000".to_string()),
+                Some("st1".to_string()),
+                "F1,2023-01-01T00:00:00,10,\"a\"",
+            )
+            .unwrap();
+        archive.append(day + Duration::hours(1), None, None, "humidity").unwrap();
+        archive.append(day + Duration::days(1), None, None, "humidity=1").unwrap();
+
+        let mut lines = Vec::new();
+        archive
+            .query(day, day + Duration::days(2), &mut |raw| {
+                lines.push(raw);
+                true
+            })
+            .unwrap();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0].id, id);
+        assert_eq!(lines[0].formation.as_deref(), Some("F1"));
+        assert_eq!(lines[0].source.as_deref(), Some("[::1]:5000"));
+        assert_eq!(lines[0].line, "F1,2023-01-01T00:00:00,10,\"a\"");
+        assert_eq!(lines[0].station.as_deref(), Some("st1"));
+        assert_eq!(lines[1].formation, None);
+        assert_eq!(lines[1].station, None);
+
+        // a new day removes the files older than keep_days
+        archive.append(day + Duration::days(3), None, None, "humidity=2").unwrap();
+        assert!(!Path::new(&format!("{root}/20230101.csv")).exists());
+        assert!(Path::new(&format!("{root}/20230102.csv")).exists());
+
+        // rows written before the station column
+        std::fs::write(
+            format!("{root}/20230103.csv"),
+            "id,received,source,formation,line\n1,2023-01-03 00:00:00.000000,,,humidity=3\n",
+        )
+        .unwrap();
+        let later = day + Duration::days(2) + Duration::hours(1);
+        archive.append(later, None, Some("st2".to_string()), "humidity=4").unwrap();
+        let mut lines = Vec::new();
+        archive
+            .query(day + Duration::days(2), day + Duration::days(3), &mut |raw| {
+                lines.push(raw);
+                true
+            })
+            .unwrap();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0].station, None);
+        assert_eq!(lines[1].station.as_deref(), Some("st2"));
+    }
+}
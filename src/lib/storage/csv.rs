@@ -0,0 +1,195 @@
+use std::{
+    collections::BTreeMap,
+    fs::OpenOptions,
+    path::Path,
+};
+
+use chrono::NaiveDateTime;
+
+use super::{
+    parse_value, partition::Partitioning, query_partitions, sort_records, value_columns,
+    QueryFilter, Record, Store,
+};
+use crate::lib::{qc_worker::QCFlag, ERROR};
+
+const HEADER: [&str; 7] = ["datetime", "parameter", "station", "value", "dtype", "flag", "raw_id"];
+
+/// Append-only csv files `{partition}.csv`, daily files `{root}/{YYYYMMDD}.csv`
+/// unless set by `set_partitioning`.
+#[derive(Debug, Clone)]
+pub struct CsvStore {
+    partition: Partitioning,
+}
+
+impl CsvStore {
+    pub fn new<S: AsRef<str>>(root: S) -> Self {
+        CsvStore {
+            partition: Partitioning::daily(root),
+        }
+    }
+
+    pub fn set_partitioning(&mut self, partition: Partitioning) {
+        self.partition = partition;
+    }
+
+    fn file_path(&self, record: &Record) -> String {
+        format!("{}.csv", self.partition.path(record))
+    }
+}
+
+/// Rows of one file matching `filter`, in time order.
+fn read_file(path: &str, filter: &QueryFilter) -> Result<Vec<Record>, ERROR> {
+    let mut rows = Vec::new();
+    // files written before raw_id have 6 columns
+    let mut reader = ::csv::ReaderBuilder::new().flexible(true).from_path(path)?;
+    for row in reader.records() {
+        let row = row?;
+        let field = |i: usize| row.get(i).unwrap_or_default();
+        let record = Record {
+            datetime: NaiveDateTime::parse_from_str(field(0), "%Y-%m-%d %H:%M:%S")
+                .map_err(|e| format!("{path}: {e}"))?,
+            parameter: field(1).to_string(),
+            station: Some(field(2).to_string()).filter(|v| !v.is_empty()),
+            value: parse_value(field(4), field(3)),
+            flag: QCFlag::from_bits_retain(field(5).parse()?),
+            raw_id: Some(field(6).to_string()).filter(|v| !v.is_empty()),
+            ..Default::default()
+        };
+        if filter.matches(&record) {
+            rows.push(record);
+        }
+    }
+
+    // appends from several saves are not in time order
+    sort_records(&mut rows);
+    Ok(rows)
+}
+
+impl Store for CsvStore {
+    fn write(&mut self, records: &[Record]) -> Result<(), ERROR> {
+        let mut files: BTreeMap<String, Vec<&Record>> = BTreeMap::new();
+        for record in records {
+            files
+                .entry(self.file_path(record))
+                .or_default()
+                .push(record);
+        }
+
+        for (path, records) in files {
+            let new_file = !Path::new(&path).exists();
+            if let Some(dir) = Path::new(&path).parent() {
+                std::fs::create_dir_all(dir)?;
+            }
+            let file = OpenOptions::new().create(true).append(true).open(&path)?;
+            let mut writer = ::csv::Writer::from_writer(file);
+            if new_file {
+                writer.write_record(HEADER)?;
+            }
+            for record in records {
+                let (dtype, value) = value_columns(&record.value);
+                writer.write_record([
+                    record.datetime.format("%Y-%m-%d %H:%M:%S").to_string(),
+                    record.parameter.clone(),
+                    record.station.clone().unwrap_or_default(),
+                    value,
+                    dtype.to_string(),
+                    record.flag.bits().to_string(),
+                    record.raw_id.clone().unwrap_or_default(),
+                ])?;
+            }
+            writer.flush()?;
+        }
+        Ok(())
+    }
+
+    fn query(
+        &self,
+        filter: &QueryFilter,
+        callback: &mut dyn FnMut(Record) -> bool,
+    ) -> Result<(), ERROR> {
+        let groups = self.partition.find(filter, Some("csv"))?;
+        query_partitions(groups, callback, &mut |path, callback| {
+            for record in read_file(path, filter)? {
+                if !callback(record) {
+                    break;
+                }
+            }
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::lib::{
+        data_parser::{parse_datetime, DataType},
+        storage::temp_root,
+    };
+
+    use super::*;
+
+    #[test]
+    fn write_and_query() {
+        let root = &temp_root("qc_csv_case1");
+
+        let mut store = CsvStore::new(root);
+        let datetime = parse_datetime("2023-01-02T00:00:10").unwrap();
+        store
+            .write(&[Record {
+                datetime,
+                parameter: "temperature".to_string(),
+                station: Some("st,1".to_string()),
+                value: DataType::Float(10.5),
+                flag: QCFlag::L1_Error,
+                ..Default::default()
+            }])
+            .unwrap();
+        store
+            .write(&[
+                Record {
+                    datetime: parse_datetime("2023-01-02T00:00:00").unwrap(),
+                    parameter: "humidity".to_string(),
+                    station: None,
+                    value: DataType::Integer(50),
+                    flag: QCFlag::new(),
+                    ..Default::default()
+                },
+                Record {
+                    datetime,
+                    parameter: "note".to_string(),
+                    station: None,
+                    value: DataType::String("a \"quoted\" text".to_string()),
+                    flag: QCFlag::new(),
+                    ..Default::default()
+                },
+            ])
+            .unwrap();
+
+        let mut filter = QueryFilter::between(
+            parse_datetime("2023-01-02").unwrap(),
+            parse_datetime("2023-01-03").unwrap(),
+        );
+        let mut rows = Vec::new();
+        store
+            .query(&filter, &mut |record| {
+                rows.push(record);
+                true
+            })
+            .unwrap();
+        assert_eq!(rows.len(), 3);
+        assert_eq!(rows[0].parameter, "humidity");
+        assert!(matches!(rows[0].value, DataType::Integer(50)));
+        assert_eq!(rows[1].value.to_string(), "a \"quoted\" text");
+        assert_eq!(rows[2].station.as_deref(), Some("st,1"));
+
+        filter.flag_mask = Some(QCFlag::L1_Error.bits());
+        let mut count = 0;
+        store
+            .query(&filter, &mut |_| {
+                count += 1;
+                true
+            })
+            .unwrap();
+        assert_eq!(count, 1);
+    }
+}
@@ -0,0 +1,318 @@
+use std::time::Duration;
+
+use chrono::NaiveDateTime;
+use serde_derive::{Deserialize, Serialize};
+
+use super::{data_parser::DataType, qc_worker::QCFlag, ERROR};
+
+pub mod archive;
+pub mod csv;
+pub mod parquet;
+pub mod partition;
+pub mod sqlite;
+
+pub use self::csv::CsvStore;
+use self::partition::{PartitionKey, Partitioning};
+pub use self::parquet::ParquetStore;
+pub use sqlite::SqliteStore;
+
+/// One stored QC value.
+#[derive(Debug, Clone, Default)]
+pub struct Record {
+    pub datetime: NaiveDateTime,
+    pub parameter: String,
+    pub station: Option<String>,
+    pub value: DataType,
+    pub flag: QCFlag,
+    /// `Global.version` of the parameter config
+    pub config_version: Option<String>,
+    /// hash of the parameter config file, see `QCConfig::hash`
+    pub config_hash: Option<String>,
+    /// result of every module, e.g. `0:boundary=pass;1:consist=fail`
+    pub outcomes: Option<String>,
+    /// the line the value was parsed from
+    pub raw: Option<String>,
+    /// id of the line in the raw archive, see `archive::RawLine`
+    pub raw_id: Option<String>,
+    /// 0, or the number of times the value was written before with `ConflictPolicy::KeepBoth`
+    pub revision: i64,
+}
+
+/// Which rows `Store::query` returns. `start` and `end` are both inclusive.
+#[derive(Debug, Clone)]
+pub struct QueryFilter {
+    pub parameters: Vec<String>,
+    pub start: NaiveDateTime,
+    pub end: NaiveDateTime,
+    pub station: Option<String>,
+    /// only rows with any of these flag bits set
+    pub flag_mask: Option<u64>,
+}
+
+impl QueryFilter {
+    /// For backends which can't filter while reading.
+    pub fn matches(&self, record: &Record) -> bool {
+        record.datetime >= self.start
+            && record.datetime <= self.end
+            && (self.parameters.is_empty() || self.parameters.contains(&record.parameter))
+            && (self.station.is_none() || self.station == record.station)
+            && self
+                .flag_mask
+                .is_none_or(|mask| record.flag.bits() & mask != 0)
+    }
+}
+
+#[cfg(test)]
+impl QueryFilter {
+    /// Every row between `start` and `end`.
+    pub fn between(start: NaiveDateTime, end: NaiveDateTime) -> Self {
+        QueryFilter {
+            parameters: Vec::new(),
+            start,
+            end,
+            station: None,
+            flag_mask: None,
+        }
+    }
+}
+
+/// Empty directory `name` in the temp dir for the files of a test.
+#[cfg(test)]
+pub fn temp_root(name: &str) -> String {
+    let root = std::env::temp_dir().join(name);
+    let _ = std::fs::remove_dir_all(&root);
+    std::fs::create_dir_all(&root).unwrap();
+    root.to_str().unwrap().to_string()
+}
+
+/// Manual change of stored flags, see `Store::override_flags`.
+#[derive(Debug, Clone)]
+pub struct FlagOverride {
+    pub set: QCFlag,
+    /// cleared after `set` is set
+    pub clear: QCFlag,
+    /// who changed the flags and why, kept in the audit record
+    pub user: String,
+    pub reason: String,
+}
+
+impl FlagOverride {
+    pub fn apply(&self, flag: QCFlag) -> QCFlag {
+        QCFlag::from_bits_retain((flag.bits() | self.set.bits()) & !self.clear.bits())
+    }
+}
+
+/// Where QC results are kept.
+pub trait Store: Send {
+    /// Write QC results.
+    fn write(&mut self, records: &[Record]) -> Result<(), ERROR>;
+
+    /// Read the rows matching `filter`, in time order.
+    /// `callback` is called for each row, returning false stops the query.
+    fn query(
+        &self,
+        filter: &QueryFilter,
+        callback: &mut dyn FnMut(Record) -> bool,
+    ) -> Result<(), ERROR>;
+
+    /// Bring files written by an older version up to the current layout.
+    fn migrate(&mut self) -> Result<(), ERROR> {
+        Ok(())
+    }
+
+    /// Write out anything the backend still buffers.
+    fn flush(&mut self) -> Result<(), ERROR> {
+        Ok(())
+    }
+
+    /// Change the flag of the stored rows matching `filter`, keeping an audit record
+    /// of each change. Returns the number of changed rows.
+    fn override_flags(&mut self, _filter: &QueryFilter, _change: &FlagOverride) -> Result<usize, ERROR> {
+        Err("Stored flags can only be changed with the sqlite backend".into())
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    #[default]
+    Sqlite,
+    Csv,
+    Parquet,
+}
+
+fn default_root() -> String {
+    "database".to_string()
+}
+
+fn default_rows_per_file() -> usize {
+    10000
+}
+
+fn default_partition() -> Vec<PartitionKey> {
+    vec![PartitionKey::Daily]
+}
+
+fn default_batch_size() -> usize {
+    500
+}
+
+fn default_flush_interval() -> u64 {
+    5
+}
+
+/// What the sqlite store does with a value it already has,
+/// i.e. the same datetime, parameter and station.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ConflictPolicy {
+    /// ignore the new value
+    KeepFirst,
+    /// overwrite the stored value
+    #[default]
+    Replace,
+    /// store the new value as the next revision, queries return the latest
+    KeepBoth,
+}
+
+/// `[storage]` of the daemon config.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct StorageConfig {
+    #[serde(default)]
+    pub backend: Backend,
+    #[serde(default = "default_root")]
+    pub root: String,
+    /// parquet only, rows buffered before a file is written
+    #[serde(default = "default_rows_per_file")]
+    pub rows_per_file: usize,
+    /// sqlite only, records inserted with one transaction
+    #[serde(default = "default_batch_size")]
+    pub batch_size: usize,
+    /// sqlite and parquet, seconds between writes of a batch when it is not full
+    #[serde(default = "default_flush_interval")]
+    pub flush_interval: u64,
+    /// sqlite only, values written again
+    #[serde(default)]
+    pub on_conflict: ConflictPolicy,
+    /// how records are split into files, see `Partitioning`
+    #[serde(default = "default_partition")]
+    pub partition: Vec<PartitionKey>,
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        StorageConfig {
+            backend: Backend::default(),
+            root: default_root(),
+            rows_per_file: default_rows_per_file(),
+            batch_size: default_batch_size(),
+            flush_interval: default_flush_interval(),
+            on_conflict: ConflictPolicy::default(),
+            partition: default_partition(),
+        }
+    }
+}
+
+impl StorageConfig {
+    /// Open the configured backend, creating `root` and migrating old files.
+    pub fn open(&self) -> Result<Box<dyn Store>, ERROR> {
+        std::fs::create_dir_all(&self.root)
+            .map_err(|e| format!("Can't create storage root {}: {e}", self.root))?;
+        let partition = Partitioning::new(&self.root, self.partition.clone())?;
+        let mut store: Box<dyn Store> = match self.backend {
+            Backend::Sqlite => {
+                let mut store = SqliteStore::with_batch(
+                    &self.root,
+                    self.batch_size,
+                    Duration::from_secs(self.flush_interval),
+                );
+                store.set_conflict(self.on_conflict);
+                store.set_partitioning(partition);
+                Box::new(store)
+            }
+            Backend::Csv => {
+                let mut store = CsvStore::new(&self.root);
+                store.set_partitioning(partition);
+                Box::new(store)
+            }
+            Backend::Parquet => {
+                let mut store = ParquetStore::new(
+                    &self.root,
+                    self.rows_per_file,
+                    Duration::from_secs(self.flush_interval),
+                );
+                store.set_partitioning(partition);
+                Box::new(store)
+            }
+        };
+        store.migrate()?;
+        Ok(store)
+    }
+}
+
+/// Row callback of `Store::query`, returning false stops the query.
+type RowCallback<'a> = dyn FnMut(Record) -> bool + 'a;
+
+/// Feed the rows of the partitions `find` gave to `callback`, in time order.
+/// `read` reads one partition in time order, partitions of the same period are merged.
+fn query_partitions(
+    groups: Vec<Vec<String>>,
+    callback: &mut dyn FnMut(Record) -> bool,
+    read: &mut dyn FnMut(&str, &mut RowCallback) -> Result<(), ERROR>,
+) -> Result<(), ERROR> {
+    for group in groups {
+        if let [path] = group.as_slice() {
+            let mut stopped = false;
+            read(path, &mut |record| {
+                stopped = !callback(record);
+                !stopped
+            })?;
+            if stopped {
+                return Ok(());
+            }
+            continue;
+        }
+
+        let mut rows = Vec::new();
+        for path in &group {
+            read(path, &mut |record| {
+                rows.push(record);
+                true
+            })?;
+        }
+        sort_records(&mut rows);
+        for record in rows {
+            if !callback(record) {
+                return Ok(());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Sort rows read from files the way `Store::query` returns them.
+fn sort_records(records: &mut [Record]) {
+    records.sort_by(|a, b| (a.datetime, &a.parameter).cmp(&(b.datetime, &b.parameter)));
+}
+
+/// Value columns of the file backends, empty for NULL.
+fn value_columns(value: &DataType) -> (&'static str, String) {
+    match value {
+        DataType::Integer(v) => ("integer", v.to_string()),
+        DataType::Float(v) => ("float", v.to_string()),
+        DataType::String(v) => ("text", v.to_string()),
+        DataType::Datetime(v) => ("datetime", v.format("%Y-%m-%dT%H:%M:%S").to_string()),
+        DataType::NULL => ("null", String::new()),
+    }
+}
+
+fn parse_value(dtype: &str, value: &str) -> DataType {
+    match dtype {
+        "integer" => value.parse().map_or(DataType::NULL, DataType::Integer),
+        "float" => value.parse().map_or(DataType::NULL, DataType::Float),
+        "text" => DataType::String(value.to_string()),
+        "datetime" => NaiveDateTime::parse_from_str(value, "%Y-%m-%dT%H:%M:%S")
+            .map_or(DataType::NULL, DataType::Datetime),
+        _ => DataType::NULL,
+    }
+}
@@ -0,0 +1,380 @@
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    path::Path,
+    sync::Arc,
+    time::{Duration as StdDuration, Instant},
+};
+
+use chrono::{DateTime, Local};
+use parquet::{
+    data_type::{ByteArray, ByteArrayType, DoubleType, Int64Type},
+    file::{
+        properties::WriterProperties,
+        reader::{FileReader, SerializedFileReader},
+        writer::SerializedFileWriter,
+    },
+    record::Field,
+    schema::parser::parse_message_type,
+};
+
+use super::{partition::Partitioning, query_partitions, sort_records, QueryFilter, Record, Store};
+use crate::lib::{data_parser::DataType, qc_worker::QCFlag, ERROR};
+
+const SCHEMA: &str = "
+message qc {
+    REQUIRED INT64 datetime (TIMESTAMP(MILLIS,false));
+    REQUIRED BYTE_ARRAY parameter (UTF8);
+    OPTIONAL BYTE_ARRAY station (UTF8);
+    OPTIONAL INT64 value_integer;
+    OPTIONAL DOUBLE value_float;
+    OPTIONAL BYTE_ARRAY value_text (UTF8);
+    REQUIRED INT64 flag;
+    OPTIONAL BYTE_ARRAY raw_id (UTF8);
+}
+";
+
+/// Parquet files in partition directories `{partition}/`, daily directories `{root}/{YYYYMMDD}/`
+/// unless set by `set_partitioning`.
+/// Records are buffered and written as a new file every `rows_per_file` records, once
+/// `flush_interval` passed and on flush, so other tools never see a partly written file.
+pub struct ParquetStore {
+    partition: Partitioning,
+    rows_per_file: usize,
+    buffer: Vec<Record>,
+    // keeps file names unique within one timestamp
+    sequence: usize,
+    flush_interval: StdDuration,
+    last_flush: Instant,
+}
+
+impl ParquetStore {
+    pub fn new<S: AsRef<str>>(root: S, rows_per_file: usize, flush_interval: StdDuration) -> Self {
+        ParquetStore {
+            partition: Partitioning::daily(root),
+            rows_per_file: rows_per_file.max(1),
+            buffer: Vec::new(),
+            sequence: 0,
+            flush_interval,
+            last_flush: Instant::now(),
+        }
+    }
+
+    pub fn set_partitioning(&mut self, partition: Partitioning) {
+        self.partition = partition;
+    }
+
+    fn write_file(&mut self, dir: &str, records: &[&Record]) -> Result<(), ERROR> {
+        std::fs::create_dir_all(dir)?;
+        self.sequence += 1;
+        let name = format!("{}-{}", Local::now().format("%Y%m%dT%H%M%S%6f"), self.sequence);
+        let tmp = format!("{dir}/.{name}.parquet.tmp");
+
+        let schema = Arc::new(parse_message_type(SCHEMA)?);
+        let props = Arc::new(WriterProperties::builder().build());
+        let mut writer = SerializedFileWriter::new(File::create(&tmp)?, schema, props)?;
+        let mut row_group = writer.next_row_group()?;
+        let mut index = 0;
+        while let Some(mut column) = row_group.next_column()? {
+            match index {
+                0 => {
+                    let values = records
+                        .iter()
+                        .map(|r| r.datetime.and_utc().timestamp_millis())
+                        .collect::<Vec<_>>();
+                    column.typed::<Int64Type>().write_batch(&values, None, None)?;
+                }
+                1 => {
+                    let values = records
+                        .iter()
+                        .map(|r| ByteArray::from(r.parameter.as_str()))
+                        .collect::<Vec<_>>();
+                    column.typed::<ByteArrayType>().write_batch(&values, None, None)?;
+                }
+                2 => {
+                    let (values, levels) =
+                        optional(records, |r| r.station.as_deref().map(ByteArray::from));
+                    column
+                        .typed::<ByteArrayType>()
+                        .write_batch(&values, Some(&levels), None)?;
+                }
+                3 => {
+                    let (values, levels) = optional(records, |r| match r.value {
+                        DataType::Integer(v) => Some(v),
+                        _ => None,
+                    });
+                    column
+                        .typed::<Int64Type>()
+                        .write_batch(&values, Some(&levels), None)?;
+                }
+                4 => {
+                    let (values, levels) = optional(records, |r| match r.value {
+                        DataType::Float(v) => Some(v),
+                        _ => None,
+                    });
+                    column
+                        .typed::<DoubleType>()
+                        .write_batch(&values, Some(&levels), None)?;
+                }
+                5 => {
+                    let (values, levels) = optional(records, |r| match &r.value {
+                        DataType::String(v) => Some(ByteArray::from(v.as_str())),
+                        _ => None,
+                    });
+                    column
+                        .typed::<ByteArrayType>()
+                        .write_batch(&values, Some(&levels), None)?;
+                }
+                6 => {
+                    let values = records
+                        .iter()
+                        .map(|r| r.flag.bits() as i64)
+                        .collect::<Vec<_>>();
+                    column.typed::<Int64Type>().write_batch(&values, None, None)?;
+                }
+                _ => {
+                    let (values, levels) =
+                        optional(records, |r| r.raw_id.as_deref().map(ByteArray::from));
+                    column
+                        .typed::<ByteArrayType>()
+                        .write_batch(&values, Some(&levels), None)?;
+                }
+            }
+            column.close()?;
+            index += 1;
+        }
+        row_group.close()?;
+        writer.close()?;
+
+        std::fs::rename(&tmp, format!("{dir}/{name}.parquet"))?;
+        Ok(())
+    }
+}
+
+/// Values and definition levels of an optional column.
+fn optional<T>(records: &[&Record], get: impl Fn(&Record) -> Option<T>) -> (Vec<T>, Vec<i16>) {
+    let mut values = Vec::new();
+    let mut levels = Vec::with_capacity(records.len());
+    for record in records {
+        match get(record) {
+            Some(v) => {
+                values.push(v);
+                levels.push(1);
+            }
+            None => levels.push(0),
+        }
+    }
+    (values, levels)
+}
+
+fn read_file(path: &Path, filter: &QueryFilter, rows: &mut Vec<Record>) -> Result<(), ERROR> {
+    let reader = SerializedFileReader::new(File::open(path)?)?;
+    for row in reader.get_row_iter(None)? {
+        let mut record = Record::default();
+        for (name, field) in row?.into_columns() {
+            match (name.as_str(), field) {
+                ("datetime", Field::TimestampMillis(v)) => {
+                    record.datetime = DateTime::from_timestamp_millis(v)
+                        .ok_or("Invalid datetime")?
+                        .naive_utc();
+                }
+                ("parameter", Field::Str(v)) => record.parameter = v,
+                ("station", Field::Str(v)) => record.station = Some(v),
+                ("value_integer", Field::Long(v)) => record.value = DataType::Integer(v),
+                ("value_float", Field::Double(v)) => record.value = DataType::Float(v),
+                ("value_text", Field::Str(v)) => record.value = DataType::String(v),
+                ("flag", Field::Long(v)) => record.flag = QCFlag::from_bits_retain(v as u64),
+                ("raw_id", Field::Str(v)) => record.raw_id = Some(v),
+                _ => {}
+            }
+        }
+        if filter.matches(&record) {
+            rows.push(record);
+        }
+    }
+    Ok(())
+}
+
+impl Store for ParquetStore {
+    fn write(&mut self, records: &[Record]) -> Result<(), ERROR> {
+        self.buffer.extend_from_slice(records);
+        if self.buffer.len() >= self.rows_per_file || self.last_flush.elapsed() >= self.flush_interval {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Only files already written are read, buffered records are not.
+    fn query(
+        &self,
+        filter: &QueryFilter,
+        callback: &mut dyn FnMut(Record) -> bool,
+    ) -> Result<(), ERROR> {
+        let groups = self.partition.find(filter, None)?;
+        query_partitions(groups, callback, &mut |dir, callback| {
+            let mut files = Vec::new();
+            for entry in std::fs::read_dir(dir)? {
+                let path = entry?.path();
+                if path.extension().is_some_and(|ext| ext == "parquet") {
+                    files.push(path);
+                }
+            }
+            files.sort();
+
+            let mut rows = Vec::new();
+            for path in files {
+                read_file(&path, filter, &mut rows)
+                    .map_err(|e| format!("{}: {e}", path.display()))?;
+            }
+            sort_records(&mut rows);
+            for record in rows {
+                if !callback(record) {
+                    break;
+                }
+            }
+            Ok(())
+        })
+    }
+
+    fn flush(&mut self) -> Result<(), ERROR> {
+        self.last_flush = Instant::now();
+        let buffer = std::mem::take(&mut self.buffer);
+        let mut dirs: BTreeMap<String, Vec<&Record>> = BTreeMap::new();
+        for record in buffer.iter() {
+            dirs.entry(self.partition.path(record))
+                .or_default()
+                .push(record);
+        }
+
+        let mut failed = Vec::new();
+        let mut error = None;
+        for (dir, records) in dirs {
+            if let Err(e) = self.write_file(&dir, &records) {
+                // no file was renamed into place, keep them for the next flush
+                failed.extend(records.into_iter().cloned());
+                error = Some(format!("{dir}: {e}"));
+            }
+        }
+        self.buffer = failed;
+        match error {
+            Some(e) => Err(e.into()),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for ParquetStore {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush() {
+            eprintln!("Failed to write parquet file: {e}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::lib::{data_parser::parse_datetime, storage::temp_root};
+
+    use super::*;
+
+    #[test]
+    fn buffered_until_rows_per_file() {
+        let root = &temp_root("qc_parquet_case1");
+
+        let mut store = ParquetStore::new(root, 2, StdDuration::from_secs(3600));
+        let datetime = parse_datetime("2023-01-02T00:00:10").unwrap();
+        store
+            .write(&[Record {
+                datetime,
+                parameter: "temperature".to_string(),
+                station: Some("st1".to_string()),
+                value: DataType::Float(10.5),
+                flag: QCFlag::L1_Error,
+                ..Default::default()
+            }])
+            .unwrap();
+
+        let filter = QueryFilter::between(
+            parse_datetime("2023-01-01").unwrap(),
+            parse_datetime("2023-01-04").unwrap(),
+        );
+        // still buffered
+        let mut count = 0;
+        store
+            .query(&filter, &mut |_| {
+                count += 1;
+                true
+            })
+            .unwrap();
+        assert_eq!(count, 0);
+
+        store
+            .write(&[
+                Record {
+                    datetime: parse_datetime("2023-01-02T00:00:00").unwrap(),
+                    parameter: "humidity".to_string(),
+                    station: None,
+                    value: DataType::Integer(50),
+                    flag: QCFlag::new(),
+                    ..Default::default()
+                },
+                Record {
+                    datetime: parse_datetime("2023-01-03T00:00:00").unwrap(),
+                    parameter: "note".to_string(),
+                    station: None,
+                    value: DataType::String("text".to_string()),
+                    flag: QCFlag::L0_Warn,
+                    ..Default::default()
+                },
+            ])
+            .unwrap();
+        let mut rows = Vec::new();
+        store
+            .query(&filter, &mut |record| {
+                rows.push(record);
+                true
+            })
+            .unwrap();
+        assert_eq!(rows.len(), 3);
+        assert_eq!(rows[0].parameter, "humidity");
+        assert!(matches!(rows[0].value, DataType::Integer(50)));
+        assert_eq!(rows[1].datetime, datetime);
+        assert_eq!(rows[1].station.as_deref(), Some("st1"));
+        assert_eq!(rows[1].flag.bits(), QCFlag::L1_Error.bits());
+        assert_eq!(rows[2].value.to_string(), "text");
+    }
+
+    #[test]
+    fn failed_flush_keeps_records() {
+        let root = &temp_root("qc_parquet_failed_flush");
+        // a file where the daily directory goes
+        std::fs::write(format!("{root}/20230102"), "").unwrap();
+
+        let mut store = ParquetStore::new(root, 10, StdDuration::ZERO);
+        let record = Record {
+            datetime: parse_datetime("2023-01-02T00:00:00").unwrap(),
+            parameter: "humidity".to_string(),
+            value: DataType::Float(50.0),
+            ..Default::default()
+        };
+        // written at once with no interval
+        assert!(store.write(&[record]).is_err());
+        assert_eq!(store.buffer.len(), 1);
+
+        std::fs::remove_file(format!("{root}/20230102")).unwrap();
+        store.flush().unwrap();
+        assert!(store.buffer.is_empty());
+        let filter = QueryFilter::between(
+            parse_datetime("2023-01-02").unwrap(),
+            parse_datetime("2023-01-03").unwrap(),
+        );
+        let mut count = 0;
+        store
+            .query(&filter, &mut |_| {
+                count += 1;
+                true
+            })
+            .unwrap();
+        assert_eq!(count, 1);
+    }
+}
@@ -0,0 +1,260 @@
+use std::path::{Path, PathBuf};
+
+use chrono::{Datelike, Duration, NaiveDate};
+use serde_derive::{Deserialize, Serialize};
+
+use super::{QueryFilter, Record};
+use crate::lib::ERROR;
+
+// file name when there are no partition keys
+const SINGLE: &str = "qc";
+// station of values which have none
+const NO_STATION: &str = "_";
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum PartitionKey {
+    /// `YYYYMMDD` of the value
+    Daily,
+    /// `YYYYMM` of the value
+    Monthly,
+    Station,
+    Parameter,
+}
+
+impl PartitionKey {
+    fn is_time(&self) -> bool {
+        matches!(self, PartitionKey::Daily | PartitionKey::Monthly)
+    }
+}
+
+/// How records are split under the storage root. Every key but the last is a directory,
+/// the last is the file (or directory, for parquet), e.g. `["station", "monthly"]` gives
+/// `{root}/{station}/{YYYYMM}`. No keys is a single `{root}/qc`.
+#[derive(Debug, Clone)]
+pub struct Partitioning {
+    root: String,
+    keys: Vec<PartitionKey>,
+}
+
+/// Keep station and parameter names from escaping the root.
+fn sanitize(name: &str) -> String {
+    name.chars()
+        .map(|c| if c.is_alphanumeric() || c == '-' || c == '_' { c } else { '_' })
+        .collect()
+}
+
+impl Partitioning {
+    pub fn new<S: AsRef<str>>(root: S, keys: Vec<PartitionKey>) -> Result<Self, ERROR> {
+        if keys.iter().filter(|key| key.is_time()).count() > 1 {
+            return Err("Only one of daily and monthly can be used to partition".into());
+        }
+        for (i, key) in keys.iter().enumerate() {
+            if keys[..i].contains(key) {
+                return Err(format!("Partition key {key:?} is given twice").into());
+            }
+        }
+        Ok(Partitioning {
+            root: root.as_ref().to_string(),
+            keys,
+        })
+    }
+
+    pub fn root(&self) -> &str {
+        &self.root
+    }
+
+    /// `{root}/{YYYYMMDD}`
+    pub fn daily<S: AsRef<str>>(root: S) -> Self {
+        Partitioning {
+            root: root.as_ref().to_string(),
+            keys: vec![PartitionKey::Daily],
+        }
+    }
+
+    /// Partition of `record`, without the extension of the backend.
+    pub fn path(&self, record: &Record) -> String {
+        let mut path = self.root.to_string();
+        for key in &self.keys {
+            let part = match key {
+                PartitionKey::Daily => record.datetime.format("%Y%m%d").to_string(),
+                PartitionKey::Monthly => record.datetime.format("%Y%m").to_string(),
+                PartitionKey::Station => sanitize(record.station.as_deref().unwrap_or(NO_STATION)),
+                PartitionKey::Parameter => sanitize(&record.parameter),
+            };
+            path = format!("{path}/{part}");
+        }
+        if self.keys.is_empty() {
+            path = format!("{path}/{SINGLE}");
+        }
+        path
+    }
+
+    /// Every partition file with `ext` under the root.
+    pub fn files(&self, ext: &str) -> Result<Vec<PathBuf>, ERROR> {
+        let mut files = Vec::new();
+        let mut dirs = vec![PathBuf::from(&self.root)];
+        while let Some(dir) = dirs.pop() {
+            if !dir.is_dir() {
+                continue;
+            }
+            for entry in std::fs::read_dir(&dir)? {
+                let path = entry?.path();
+                if path.is_dir() {
+                    dirs.push(path);
+                } else if path.extension().is_some_and(|v| v == ext) {
+                    files.push(path);
+                }
+            }
+        }
+        files.sort();
+        Ok(files)
+    }
+
+    /// Time partitions between `start` and `end`, in time order.
+    fn periods(&self, start: NaiveDate, end: NaiveDate) -> Vec<Option<String>> {
+        let mut periods = Vec::new();
+        match self.keys.iter().find(|key| key.is_time()) {
+            Some(PartitionKey::Daily) => {
+                let mut date = start;
+                while date <= end {
+                    periods.push(Some(date.format("%Y%m%d").to_string()));
+                    date += Duration::days(1);
+                }
+            }
+            Some(_) => {
+                let (mut year, mut month) = (start.year(), start.month());
+                while (year, month) <= (end.year(), end.month()) {
+                    periods.push(Some(format!("{year:04}{month:02}")));
+                    (year, month) = if month == 12 { (year + 1, 1) } else { (year, month + 1) };
+                }
+            }
+            None => periods.push(None),
+        }
+        periods
+    }
+
+    /// Existing partitions which can hold rows of `filter`, grouped by time period in time order.
+    /// `ext` is the extension of partition files, `None` when partitions are directories.
+    pub fn find(&self, filter: &QueryFilter, ext: Option<&str>) -> Result<Vec<Vec<String>>, ERROR> {
+        let with_ext = |path: &str| match ext {
+            Some(ext) => format!("{path}.{ext}"),
+            None => path.to_string(),
+        };
+        if self.keys.is_empty() {
+            let path = with_ext(&format!("{}/{SINGLE}", self.root));
+            return Ok(if Path::new(&path).exists() { vec![vec![path]] } else { Vec::new() });
+        }
+
+        let mut groups = Vec::new();
+        for period in self.periods(filter.start.date(), filter.end.date()) {
+            let mut candidates = vec![self.root.to_string()];
+            for (i, key) in self.keys.iter().enumerate() {
+                let last = i + 1 == self.keys.len();
+                let mut next = Vec::new();
+                for dir in candidates {
+                    let parts = match key {
+                        PartitionKey::Daily | PartitionKey::Monthly => period.iter().cloned().collect(),
+                        PartitionKey::Station if filter.station.is_some() => {
+                            filter.station.iter().map(|v| sanitize(v)).collect()
+                        }
+                        PartitionKey::Parameter if !filter.parameters.is_empty() => {
+                            filter.parameters.iter().map(|v| sanitize(v)).collect()
+                        }
+                        _ => list(&dir, if last { ext } else { None })?,
+                    };
+                    next.extend(parts.into_iter().map(|part| format!("{dir}/{part}")));
+                }
+                candidates = next;
+            }
+
+            let mut group = candidates
+                .iter()
+                .map(|path| with_ext(path))
+                .filter(|path| Path::new(path).exists())
+                .collect::<Vec<_>>();
+            group.sort();
+            if !group.is_empty() {
+                groups.push(group);
+            }
+        }
+        Ok(groups)
+    }
+}
+
+/// Names of the partitions in `dir`, files with `ext` or directories.
+fn list(dir: &str, ext: Option<&str>) -> Result<Vec<String>, ERROR> {
+    let mut names = Vec::new();
+    if !Path::new(dir).is_dir() {
+        return Ok(names);
+    }
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        let name = match ext {
+            Some(ext) if path.is_file() && path.extension().is_some_and(|v| v == ext) => {
+                path.file_stem()
+            }
+            None if path.is_dir() => path.file_name(),
+            _ => None,
+        };
+        if let Some(name) = name.and_then(|v| v.to_str()) {
+            names.push(name.to_string());
+        }
+    }
+    Ok(names)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::lib::{data_parser::parse_datetime, storage::temp_root};
+
+    use super::*;
+
+    #[test]
+    fn station_monthly_paths() {
+        let root = &temp_root("qc_partition_case1");
+
+        let partition =
+            Partitioning::new(root, vec![PartitionKey::Station, PartitionKey::Monthly]).unwrap();
+        let record = |datetime: &str, station: Option<&str>| Record {
+            datetime: parse_datetime(datetime).unwrap(),
+            parameter: "temperature".to_string(),
+            station: station.map(|v| v.to_string()),
+            ..Default::default()
+        };
+        let records = [
+            record("2023-01-31", Some("st/1")),
+            record("2023-02-01", Some("st2")),
+            record("2023-03-01", None),
+        ];
+        assert_eq!(partition.path(&records[0]), format!("{root}/st_1/202301"));
+        assert_eq!(partition.path(&records[2]), format!("{root}/_/202303"));
+        for record in &records {
+            let path = format!("{}.db", partition.path(record));
+            std::fs::create_dir_all(Path::new(&path).parent().unwrap()).unwrap();
+            std::fs::write(path, "").unwrap();
+        }
+
+        let mut filter = QueryFilter::between(
+            parse_datetime("2022-12-01").unwrap(),
+            parse_datetime("2023-02-15").unwrap(),
+        );
+        let groups = partition.find(&filter, Some("db")).unwrap();
+        assert_eq!(
+            groups,
+            vec![
+                vec![format!("{root}/st_1/202301.db")],
+                vec![format!("{root}/st2/202302.db")],
+            ]
+        );
+
+        filter.end = parse_datetime("2023-12-31").unwrap();
+        filter.station = Some("st2".to_string());
+        let groups = partition.find(&filter, Some("db")).unwrap();
+        assert_eq!(groups, vec![vec![format!("{root}/st2/202302.db")]]);
+
+        assert!(Partitioning::new(root, vec![PartitionKey::Daily, PartitionKey::Monthly]).is_err());
+        let single = Partitioning::new(root, Vec::new()).unwrap();
+        assert_eq!(single.path(&records[0]), format!("{root}/qc"));
+    }
+}
@@ -0,0 +1,1061 @@
+use std::{
+    collections::BTreeMap,
+    path::Path,
+    time::{Duration as StdDuration, Instant},
+};
+
+use chrono::NaiveDateTime;
+use sqlite::{Connection, OpenFlags, State, Statement};
+
+use super::{
+    partition::Partitioning, query_partitions, ConflictPolicy, CsvStore, FlagOverride, QueryFilter,
+    Record, Store,
+};
+use crate::lib::{data_parser::DataType, qc_worker::QCFlag, ERROR};
+
+// files kept open, the least recently used is closed first
+const MAX_CONNECTIONS: usize = 4;
+// flushes in a row a file may fail before its records are moved to `{root}/rejected/`
+const MAX_ATTEMPTS: usize = 5;
+// how long a connection waits for a reader or another writer
+const BUSY_TIMEOUT_MS: usize = 5000;
+const TABLES: [&str; 3] = ["IntegerTable", "FloatTable", "TextTable"];
+
+/// Sqlite files `{partition}.db`, daily files `{root}/{YYYYMMDD}.db` unless set by `set_partitioning`.
+///
+/// Connections stay open between writes. Records are buffered until `batch_size`
+/// records or `flush_interval` is reached and inserted with one transaction per file,
+/// `new` writes every call through.
+///
+/// Records of a file which fails `MAX_ATTEMPTS` flushes in a row are written to the csv files
+/// of `{root}/rejected/` instead.
+///
+/// A value is identified by datetime, parameter and station, what happens when it is
+/// written again is set by `set_conflict`.
+pub struct SqliteStore {
+    partition: Partitioning,
+    conflict: ConflictPolicy,
+    // least recently used first
+    connections: Vec<(String, Connection)>,
+    // failed flushes in a row by file
+    attempts: BTreeMap<String, usize>,
+    buffer: Vec<Record>,
+    batch_size: usize,
+    flush_interval: StdDuration,
+    last_flush: Instant,
+}
+
+impl SqliteStore {
+    pub fn new<S: AsRef<str>>(root: S) -> Self {
+        SqliteStore::with_batch(root, 0, StdDuration::ZERO)
+    }
+
+    pub fn with_batch<S: AsRef<str>>(root: S, batch_size: usize, flush_interval: StdDuration) -> Self {
+        SqliteStore {
+            partition: Partitioning::daily(root),
+            conflict: ConflictPolicy::default(),
+            connections: Vec::new(),
+            attempts: BTreeMap::new(),
+            buffer: Vec::new(),
+            batch_size,
+            flush_interval,
+            last_flush: Instant::now(),
+        }
+    }
+
+    pub fn set_conflict(&mut self, conflict: ConflictPolicy) {
+        self.conflict = conflict;
+    }
+
+    pub fn set_partitioning(&mut self, partition: Partitioning) {
+        self.partition = partition;
+    }
+
+    fn db_path(&self, record: &Record) -> String {
+        format!("{}.db", self.partition.path(record))
+    }
+
+    fn connection(&mut self, db_path: &str) -> Result<&Connection, ERROR> {
+        match self.connections.iter().position(|(path, _)| path == db_path) {
+            Some(index) => {
+                let used = self.connections.remove(index);
+                self.connections.push(used);
+            }
+            None => {
+                if self.connections.len() >= MAX_CONNECTIONS {
+                    self.connections.remove(0);
+                }
+                if let Some(dir) = Path::new(db_path).parent() {
+                    std::fs::create_dir_all(dir)?;
+                }
+                self.connections.push((db_path.to_string(), db_get(db_path)?));
+            }
+        }
+        Ok(&self.connections.last().unwrap().1)
+    }
+
+    /// Append records which can't be inserted to the csv files of `{root}/rejected/`.
+    fn reject(&self, records: &[&Record]) -> Result<(), ERROR> {
+        let mut store = CsvStore::new(format!("{}/rejected", self.partition.root()));
+        store.write(&records.iter().map(|&record| record.clone()).collect::<Vec<_>>())
+    }
+
+    /// Insert the records of one file in a single transaction.
+    fn insert(&mut self, db_path: &str, records: &[&Record]) -> Result<(), ERROR> {
+        let conflict = self.conflict;
+        let conn = self.connection(db_path)?;
+        conn.execute("BEGIN")?;
+        let result = insert_records(conn, conflict, records);
+        match result {
+            Ok(_) => Ok(conn.execute("COMMIT")?),
+            Err(e) => {
+                let _ = conn.execute("ROLLBACK");
+                Err(e.into())
+            }
+        }
+    }
+}
+
+// rows of the same value as the one bound, `T` is the table inserted into
+const SAME_KEY: &str = "datetime = :datetime AND parameter = :parameter AND ifnull(station, '') = ifnull(:station, '')";
+
+fn insert_records(
+    conn: &Connection,
+    conflict: ConflictPolicy,
+    records: &[&Record],
+) -> sqlite::Result<()> {
+    // one prepared statement per table, reused for every record
+    let mut statements: [Option<Statement>; 3] = [None, None, None];
+    for record in records {
+        let table = match table_name(&record.value) {
+            Some(v) => v,
+            None => continue,
+        };
+        let index = TABLES.iter().position(|v| *v == table).unwrap();
+        if statements[index].is_none() {
+            let (insert, revision) = match conflict {
+                ConflictPolicy::KeepFirst => ("INSERT OR IGNORE", "0".to_string()),
+                // the latest revision is overwritten
+                ConflictPolicy::Replace => (
+                    "INSERT OR REPLACE",
+                    format!("(SELECT ifnull(max(revision), 0) FROM {table} WHERE {SAME_KEY})"),
+                ),
+                ConflictPolicy::KeepBoth => (
+                    "INSERT",
+                    format!("(SELECT ifnull(max(revision) + 1, 0) FROM {table} WHERE {SAME_KEY})"),
+                ),
+            };
+            statements[index] = Some(conn.prepare(format!(
+                "{insert} INTO {table} (datetime, parameter, station, value, flag, config_version, config_hash, outcomes, raw, raw_id, revision)
+                VALUES (:datetime, :parameter, :station, :value, :flag, :config_version, :config_hash, :outcomes, :raw, :raw_id, {revision})"
+            ))?);
+        }
+        let statement = statements[index].as_mut().unwrap();
+        statement.reset()?;
+        bind_record(statement, record)?;
+        statement.next()?;
+    }
+    Ok(())
+}
+
+fn table_name(data: &DataType) -> Option<&'static str> {
+    match data {
+        DataType::Integer(_) => Some("IntegerTable"),
+        // gap records have no value
+        DataType::Float(_) | DataType::NULL => Some("FloatTable"),
+        DataType::String(_) => Some("TextTable"),
+        DataType::Datetime(_) => None,
+    }
+}
+
+fn bind_record(statement: &mut Statement, record: &Record) -> sqlite::Result<()> {
+    let datetime = record.datetime.format("%Y-%m-%d %H:%M:%S").to_string();
+    statement.bind((":datetime", datetime.as_str()))?;
+    statement.bind((":parameter", record.parameter.as_str()))?;
+    statement.bind((":station", record.station.as_deref()))?;
+    match &record.value {
+        DataType::Integer(v) => statement.bind((":value", *v))?,
+        DataType::Float(v) => statement.bind((":value", *v))?,
+        DataType::String(v) => statement.bind((":value", v.as_str()))?,
+        DataType::Datetime(_) | DataType::NULL => statement.bind((":value", ()))?,
+    }
+    statement.bind((":flag", record.flag.bits() as i64))?;
+    statement.bind((":config_version", record.config_version.as_deref()))?;
+    statement.bind((":config_hash", record.config_hash.as_deref()))?;
+    statement.bind((":outcomes", record.outcomes.as_deref()))?;
+    statement.bind((":raw", record.raw.as_deref()))?;
+    statement.bind((":raw_id", record.raw_id.as_deref()))?;
+    Ok(())
+}
+
+impl Store for SqliteStore {
+    fn write(&mut self, records: &[Record]) -> Result<(), ERROR> {
+        self.buffer.extend_from_slice(records);
+        if self.buffer.len() >= self.batch_size || self.last_flush.elapsed() >= self.flush_interval {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn query(
+        &self,
+        filter: &QueryFilter,
+        callback: &mut dyn FnMut(Record) -> bool,
+    ) -> Result<(), ERROR> {
+        db_query(&self.partition, filter, callback)
+    }
+
+    fn migrate(&mut self) -> Result<(), ERROR> {
+        for path in self.partition.files("db")? {
+            db_get(&path).map_err(|e| format!("{}: {e}", path.display()))?;
+        }
+        Ok(())
+    }
+
+    fn override_flags(&mut self, filter: &QueryFilter, change: &FlagOverride) -> Result<usize, ERROR> {
+        // buffered rows can be changed too
+        self.flush()?;
+        let mut changed = 0;
+        for db_path in self.partition.find(filter, Some("db"))?.into_iter().flatten() {
+            let conn = self.connection(&db_path)?;
+            conn.execute("BEGIN")?;
+            match override_file(conn, filter, change) {
+                Ok(n) => {
+                    conn.execute("COMMIT")?;
+                    changed += n;
+                }
+                Err(e) => {
+                    let _ = conn.execute("ROLLBACK");
+                    return Err(format!("{db_path}: {e}").into());
+                }
+            }
+        }
+        Ok(changed)
+    }
+
+    fn flush(&mut self) -> Result<(), ERROR> {
+        self.last_flush = Instant::now();
+        let buffer = std::mem::take(&mut self.buffer);
+        let mut files: BTreeMap<String, Vec<&Record>> = BTreeMap::new();
+        for record in buffer.iter() {
+            files
+                .entry(self.db_path(record))
+                .or_default()
+                .push(record);
+        }
+
+        let mut failed = Vec::new();
+        let mut error = None;
+        for (db_path, records) in files {
+            let Err(e) = self.insert(&db_path, &records) else {
+                self.attempts.remove(&db_path);
+                continue;
+            };
+            let attempts = self.attempts.entry(db_path.to_string()).or_default();
+            *attempts += 1;
+            if *attempts < MAX_ATTEMPTS {
+                // rolled back, keep them for the next flush
+                failed.extend(records.into_iter().cloned());
+                error = Some(format!("{db_path}: {e}"));
+                continue;
+            }
+            self.attempts.remove(&db_path);
+            match self.reject(&records) {
+                Ok(()) => error = Some(format!("{db_path}: {e}, {} records rejected", records.len())),
+                Err(reject) => {
+                    failed.extend(records.into_iter().cloned());
+                    error = Some(format!("{db_path}: {e}, not rejected either: {reject}"));
+                }
+            }
+        }
+        self.buffer = failed;
+        match error {
+            Some(e) => Err(e.into()),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for SqliteStore {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush() {
+            eprintln!("Failed to write sqlite database: {e}");
+        }
+    }
+}
+
+fn capitalize(s: &str) -> String {
+    let mut c = s.chars();
+    match c.next() {
+        None => String::new(),
+        Some(f) => f.to_uppercase().collect::<String>() + c.as_str(),
+    }
+}
+
+/// Forward migrations, the file is at version `n` after the first `n` ran.
+/// Never edit a released migration, append a new one.
+const MIGRATIONS: [fn(&Connection) -> sqlite::Result<()>; 7] = [
+    // 1: value tables
+    |conn| {
+        for dtype in ["integer", "float", "text"] {
+            conn.execute(format!(
+                "CREATE TABLE IF NOT EXISTS {}Table (
+                    id integer primary key autoincrement,
+                    datetime text,
+                    parameter text,
+                    value {dtype},
+                    flag UNSIGNED BIG INT
+                )",
+                capitalize(dtype)
+            ))?;
+        }
+        Ok(())
+    },
+    // 2: station
+    |conn| add_column(conn, "station text"),
+    // 3: reads are by parameter and time range
+    |conn| {
+        for table in TABLES {
+            conn.execute(format!(
+                "CREATE INDEX IF NOT EXISTS {table}_parameter_datetime ON {table} (parameter, datetime)"
+            ))?;
+        }
+        Ok(())
+    },
+    // 4: which config and modules produced the row, and the line it came from
+    |conn| {
+        for column in ["config_version text", "config_hash text", "outcomes text", "raw text"] {
+            add_column(conn, column)?;
+        }
+        Ok(())
+    },
+    // 5: one row per value and revision, rows written twice before become revisions
+    |conn| {
+        add_column(conn, "revision integer NOT NULL DEFAULT 0")?;
+        for table in TABLES {
+            conn.execute(format!(
+                "UPDATE {table} SET revision = (
+                    SELECT count(*) FROM {table} AS o
+                    WHERE o.datetime = {table}.datetime AND o.parameter = {table}.parameter
+                        AND ifnull(o.station, '') = ifnull({table}.station, '') AND o.id < {table}.id
+                )"
+            ))?;
+            conn.execute(format!(
+                "CREATE UNIQUE INDEX IF NOT EXISTS {table}_key ON {table} (datetime, parameter, ifnull(station, ''), revision)"
+            ))?;
+        }
+        Ok(())
+    },
+    // 6: link to the raw archive
+    |conn| add_column(conn, "raw_id text"),
+    // 7: who changed stored flags by hand and why
+    |conn| {
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS flag_audit (
+                id integer primary key autoincrement,
+                changed text,
+                user text,
+                reason text,
+                datetime text,
+                parameter text,
+                station text,
+                revision integer,
+                old_flag UNSIGNED BIG INT,
+                new_flag UNSIGNED BIG INT
+            )",
+        )?;
+        Ok(())
+    },
+];
+
+const SCHEMA_VERSION: i64 = MIGRATIONS.len() as i64;
+
+fn add_column(conn: &Connection, column: &str) -> sqlite::Result<()> {
+    let name = column.split_whitespace().next().unwrap_or_default();
+    for table in TABLES {
+        if !has_column(conn, table, name)? {
+            conn.execute(format!("ALTER TABLE {table} ADD COLUMN {column}"))?;
+        }
+    }
+    Ok(())
+}
+
+/// Version of a file, files written before `schema_version` existed are 0 or 1.
+fn schema_version(conn: &Connection) -> sqlite::Result<i64> {
+    conn.execute("CREATE TABLE IF NOT EXISTS schema_version (version integer, applied text)")?;
+    if let Some(version) = recorded_version(conn)? {
+        return Ok(version);
+    }
+    if !has_column(conn, "FloatTable", "value")? {
+        Ok(0)
+    } else {
+        Ok(1)
+    }
+}
+
+/// Last migration recorded in `schema_version`, none when the table is missing or empty.
+fn recorded_version(conn: &Connection) -> sqlite::Result<Option<i64>> {
+    let mut exists = false;
+    conn.iterate(
+        "SELECT name FROM sqlite_master WHERE type = 'table' AND name = 'schema_version'",
+        |_| {
+            exists = true;
+            true
+        },
+    )?;
+    if !exists {
+        return Ok(None);
+    }
+    let mut statement = conn.prepare("SELECT max(version) AS version FROM schema_version")?;
+    if let State::Row = statement.next()? {
+        return statement.read::<Option<i64>, _>("version");
+    }
+    Ok(None)
+}
+
+fn migrate(conn: &Connection) -> sqlite::Result<()> {
+    let version = schema_version(conn)?;
+    if version > SCHEMA_VERSION {
+        return Err(sqlite::Error {
+            code: None,
+            message: Some(format!(
+                "schema version {version} is newer than {SCHEMA_VERSION} of this build"
+            )),
+        });
+    }
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        conn.execute("BEGIN")?;
+        let result = migration(conn).and_then(|_| {
+            let mut statement = conn.prepare(
+                "INSERT INTO schema_version (version, applied) VALUES (:version, datetime('now'))",
+            )?;
+            statement.bind((":version", index as i64 + 1))?;
+            statement.next()?;
+            Ok(())
+        });
+        match result {
+            Ok(_) => conn.execute("COMMIT")?,
+            Err(e) => {
+                let _ = conn.execute("ROLLBACK");
+                return Err(e);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn db_get<T: AsRef<Path>>(path: T) -> sqlite::Result<Connection> {
+    let mut conn = sqlite::Connection::open(path)?;
+    conn.set_busy_timeout(BUSY_TIMEOUT_MS)?;
+    // readers (query, export) don't block the writer
+    conn.execute("PRAGMA journal_mode=WAL")?;
+    migrate(&conn)?;
+    Ok(conn)
+}
+
+/// Open a file for a query, without the write locks of `db_get`. Files are migrated by
+/// `Store::migrate` and when they are written.
+fn db_read<T: AsRef<Path>>(path: T) -> sqlite::Result<Connection> {
+    let mut conn = Connection::open_with_flags(path, OpenFlags::new().with_read_only())?;
+    conn.set_busy_timeout(BUSY_TIMEOUT_MS)?;
+    match recorded_version(&conn)? {
+        Some(SCHEMA_VERSION) => Ok(conn),
+        version => Err(sqlite::Error {
+            code: None,
+            message: Some(format!(
+                "schema version {} is not {SCHEMA_VERSION} of this build, migrate the file first",
+                version.unwrap_or_default()
+            )),
+        }),
+    }
+}
+
+fn has_column(conn: &Connection, table: &str, column: &str) -> sqlite::Result<bool> {
+    let mut found = false;
+    conn.iterate(format!("PRAGMA table_info({table})"), |pairs| {
+        if pairs.iter().any(|&(key, value)| key == "name" && value == Some(column)) {
+            found = true;
+        }
+        true
+    })?;
+    Ok(found)
+}
+
+/// Read the rows matching `filter` from the partitions, in time order.
+/// `callback` is called for each row, returning false stops the query.
+fn db_query(
+    partition: &Partitioning,
+    filter: &QueryFilter,
+    callback: &mut dyn FnMut(Record) -> bool,
+) -> Result<(), ERROR> {
+    let conditions = filter_conditions(filter);
+    let query = ["integer", "float", "text"]
+        .iter()
+        .map(|dtype| {
+            let table = format!("{}Table", capitalize(dtype));
+            format!(
+                "SELECT datetime, parameter, station, value, flag, config_version, config_hash, outcomes, raw, raw_id, revision, '{dtype}' AS dtype
+                FROM {table} WHERE {conditions} AND {}",
+                LATEST.replace("{table}", &table)
+            )
+        })
+        .collect::<Vec<_>>()
+        .join(" UNION ALL ")
+        + " ORDER BY datetime, parameter";
+
+    let groups = partition.find(filter, Some("db"))?;
+    query_partitions(groups, callback, &mut |db_path, callback| {
+        query_file(db_path, &query, filter, callback)
+            .map_err(|e| format!("{db_path}: {e}").into())
+    })
+}
+
+// only the latest revision of a row of `{table}`
+const LATEST: &str = "NOT EXISTS (
+    SELECT 1 FROM {table} AS n
+    WHERE n.datetime = {table}.datetime AND n.parameter = {table}.parameter
+        AND ifnull(n.station, '') = ifnull({table}.station, '') AND n.revision > {table}.revision
+)";
+
+/// Where clause of `filter`, bound by `bind_filter`.
+fn filter_conditions(filter: &QueryFilter) -> String {
+    let mut conditions = vec!["datetime >= :start".to_string(), "datetime <= :end".to_string()];
+    if filter.station.is_some() {
+        conditions.push("station = :station".to_string());
+    }
+    if filter.flag_mask.is_some() {
+        conditions.push("(flag & :mask) != 0".to_string());
+    }
+    if !filter.parameters.is_empty() {
+        let placeholders = (0..filter.parameters.len())
+            .map(|i| format!(":p{i}"))
+            .collect::<Vec<_>>()
+            .join(", ");
+        conditions.push(format!("parameter IN ({placeholders})"));
+    }
+    conditions.join(" AND ")
+}
+
+fn bind_filter(statement: &mut Statement, filter: &QueryFilter) -> sqlite::Result<()> {
+    let start = filter.start.format("%Y-%m-%d %H:%M:%S").to_string();
+    let end = filter.end.format("%Y-%m-%d %H:%M:%S").to_string();
+    statement.bind((":start", start.as_str()))?;
+    statement.bind((":end", end.as_str()))?;
+    if let Some(station) = &filter.station {
+        statement.bind((":station", station.as_str()))?;
+    }
+    if let Some(mask) = filter.flag_mask {
+        statement.bind((":mask", mask as i64))?;
+    }
+    for (i, parameter) in filter.parameters.iter().enumerate() {
+        statement.bind((format!(":p{i}").as_str(), parameter.as_str()))?;
+    }
+    Ok(())
+}
+
+/// Change the flag of the latest revision of the rows matching `filter` in one file,
+/// with an audit row per changed row. Returns the number of changed rows.
+fn override_file(conn: &Connection, filter: &QueryFilter, change: &FlagOverride) -> sqlite::Result<usize> {
+    let changed_at = chrono::offset::Local::now()
+        .naive_local()
+        .format("%Y-%m-%d %H:%M:%S")
+        .to_string();
+    let mut audit = conn.prepare(
+        "INSERT INTO flag_audit (changed, user, reason, datetime, parameter, station, revision, old_flag, new_flag)
+        VALUES (:changed, :user, :reason, :datetime, :parameter, :station, :revision, :old_flag, :new_flag)",
+    )?;
+    let mut changed = 0;
+    for table in TABLES {
+        let mut statement = conn.prepare(format!(
+            "SELECT id, datetime, parameter, station, revision, flag FROM {table} WHERE {} AND {}",
+            filter_conditions(filter),
+            LATEST.replace("{table}", table)
+        ))?;
+        bind_filter(&mut statement, filter)?;
+        let mut rows = Vec::new();
+        while let State::Row = statement.next()? {
+            rows.push((
+                statement.read::<i64, _>("id")?,
+                statement.read::<String, _>("datetime")?,
+                statement.read::<String, _>("parameter")?,
+                statement.read::<Option<String>, _>("station")?,
+                statement.read::<i64, _>("revision")?,
+                statement.read::<i64, _>("flag")?,
+            ));
+        }
+
+        let mut update = conn.prepare(format!("UPDATE {table} SET flag = :flag WHERE id = :id"))?;
+        for (id, datetime, parameter, station, revision, flag) in rows {
+            let new_flag = change.apply(QCFlag::from_bits_retain(flag as u64)).bits() as i64;
+            if new_flag == flag {
+                continue;
+            }
+            update.reset()?;
+            update.bind((":flag", new_flag))?;
+            update.bind((":id", id))?;
+            update.next()?;
+
+            audit.reset()?;
+            audit.bind((":changed", changed_at.as_str()))?;
+            audit.bind((":user", change.user.as_str()))?;
+            audit.bind((":reason", change.reason.as_str()))?;
+            audit.bind((":datetime", datetime.as_str()))?;
+            audit.bind((":parameter", parameter.as_str()))?;
+            audit.bind((":station", station.as_deref()))?;
+            audit.bind((":revision", revision))?;
+            audit.bind((":old_flag", flag))?;
+            audit.bind((":new_flag", new_flag))?;
+            audit.next()?;
+            changed += 1;
+        }
+    }
+    Ok(changed)
+}
+
+fn query_file(
+    db_path: &str,
+    query: &str,
+    filter: &QueryFilter,
+    callback: &mut dyn FnMut(Record) -> bool,
+) -> sqlite::Result<()> {
+    let conn = db_read(db_path)?;
+    let mut statement = conn.prepare(query)?;
+    bind_filter(&mut statement, filter)?;
+
+    while let State::Row = statement.next()? {
+        let value = match statement.read::<String, _>("dtype")?.as_str() {
+            "integer" => statement
+                .read::<Option<i64>, _>("value")?
+                .map_or(DataType::NULL, DataType::Integer),
+            "float" => statement
+                .read::<Option<f64>, _>("value")?
+                .map_or(DataType::NULL, DataType::Float),
+            _ => statement
+                .read::<Option<String>, _>("value")?
+                .map_or(DataType::NULL, DataType::String),
+        };
+        let datetime = statement.read::<String, _>("datetime")?;
+        let record = Record {
+            datetime: NaiveDateTime::parse_from_str(&datetime, "%Y-%m-%d %H:%M:%S")
+                .unwrap_or_default(),
+            parameter: statement.read::<String, _>("parameter")?,
+            station: statement.read::<Option<String>, _>("station")?,
+            value,
+            flag: QCFlag::from_bits_retain(statement.read::<i64, _>("flag")? as u64),
+            config_version: statement.read::<Option<String>, _>("config_version")?,
+            config_hash: statement.read::<Option<String>, _>("config_hash")?,
+            outcomes: statement.read::<Option<String>, _>("outcomes")?,
+            raw: statement.read::<Option<String>, _>("raw")?,
+            raw_id: statement.read::<Option<String>, _>("raw_id")?,
+            revision: statement.read::<i64, _>("revision")?,
+        };
+        if !callback(record) {
+            return Ok(());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use chrono::Duration;
+
+    use crate::lib::{
+        data_parser::parse_datetime,
+        qc_worker::QCworker,
+        storage::{partition::PartitionKey, temp_root},
+    };
+
+    use super::*;
+
+    #[test]
+    fn worker_saves_and_queries() {
+        let root = &temp_root("qc_database_case1");
+
+        let mut qc = QCworker::new(HashMap::new());
+        qc.set_database(root);
+        qc.set_station(Some("st1".to_string()));
+        for line in ["F1,2023-01-02T00:01:04,10.2,50", "F1,2023-01-03T00:01:04,10.3,51"] {
+            qc.handler(line).unwrap();
+            qc.save().unwrap();
+        }
+
+        let store = SqliteStore::new(root);
+        let mut filter = QueryFilter {
+            parameters: vec!["humidity".to_string()],
+            station: Some("st1".to_string()),
+            ..QueryFilter::between(
+                parse_datetime("2023-01-01").unwrap(),
+                parse_datetime("2023-01-04").unwrap(),
+            )
+        };
+        let mut rows = Vec::new();
+        store
+            .query(&filter, &mut |record| {
+                rows.push(record);
+                true
+            })
+            .unwrap();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[1].value.to_string(), "51");
+        assert_eq!(rows[1].raw.as_deref(), Some("F1,2023-01-03T00:01:04,10.3,51"));
+        assert!(rows[1].config_hash.is_some());
+        assert!(rows[1].outcomes.is_some());
+
+        filter.station = Some("st2".to_string());
+        let mut count = 0;
+        store
+            .query(&filter, &mut |_| {
+                count += 1;
+                true
+            })
+            .unwrap();
+        assert_eq!(count, 0);
+    }
+
+    #[test]
+    fn values_bound_not_quoted() {
+        let root = &temp_root("qc_database_case2");
+
+        // quotes must not break the statement
+        let datetime = parse_datetime("2023-01-02T00:00:00").unwrap();
+        let mut store = SqliteStore::new(root);
+        let records = vec![
+            Record {
+                datetime,
+                parameter: "it's".to_string(),
+                station: Some("o'hare".to_string()),
+                value: DataType::String("'); DROP TABLE FloatTable; --".to_string()),
+                flag: QCFlag::new(),
+                ..Default::default()
+            },
+            Record {
+                datetime,
+                parameter: "count".to_string(),
+                station: None,
+                value: DataType::Integer(3),
+                flag: QCFlag::L0_Warn,
+                ..Default::default()
+            },
+        ];
+        store.write(&records).unwrap();
+
+        let filter = QueryFilter::between(datetime, datetime);
+        let mut rows = Vec::new();
+        store
+            .query(&filter, &mut |record| {
+                rows.push(record);
+                true
+            })
+            .unwrap();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0].parameter, "count");
+        assert_eq!(rows[0].flag.bits(), QCFlag::L0_Warn.bits());
+        assert_eq!(rows[1].parameter, "it's");
+        assert_eq!(rows[1].station.as_deref(), Some("o'hare"));
+        assert_eq!(rows[1].value.to_string(), "'); DROP TABLE FloatTable; --");
+    }
+
+    #[test]
+    fn batched_writes_flushed() {
+        let root = &temp_root("qc_database_case3");
+
+        let datetime = parse_datetime("2023-01-02T00:00:00").unwrap();
+        let record = |minute: i64| Record {
+            datetime: datetime + Duration::minutes(minute),
+            parameter: "temperature".to_string(),
+            station: None,
+            value: DataType::Float(minute as f64),
+            flag: QCFlag::new(),
+            ..Default::default()
+        };
+        let filter = QueryFilter::between(datetime, datetime + Duration::days(1));
+        let count = || {
+            let mut count = 0;
+            SqliteStore::new(root)
+                .query(&filter, &mut |_| {
+                    count += 1;
+                    true
+                })
+                .unwrap();
+            count
+        };
+
+        let mut store = SqliteStore::with_batch(root, 3, StdDuration::from_secs(3600));
+        store.write(&[record(0), record(1)]).unwrap();
+        assert_eq!(count(), 0);
+        store.write(&[record(2)]).unwrap();
+        assert_eq!(count(), 3);
+
+        store.write(&[record(3)]).unwrap();
+        drop(store);
+        assert_eq!(count(), 4);
+
+        let conn = db_get(format!("{root}/20230102.db")).unwrap();
+        let mut statement = conn.prepare("PRAGMA journal_mode").unwrap();
+        statement.next().unwrap();
+        assert_eq!(statement.read::<String, _>(0).unwrap(), "wal");
+    }
+
+    #[test]
+    fn migrates_old_files() {
+        let root = &temp_root("qc_database_case4");
+        let db_path = format!("{root}/20230102.db");
+
+        // a file written before schema_version
+        let conn = sqlite::Connection::open(&db_path).unwrap();
+        for dtype in ["integer", "float", "text"] {
+            conn.execute(format!(
+                "CREATE TABLE {}Table (id integer primary key autoincrement, datetime text, parameter text, value {dtype}, flag UNSIGNED BIG INT)",
+                capitalize(dtype)
+            ))
+            .unwrap();
+        }
+        // written twice, there was no unique key
+        for value in [1.5, 2.5] {
+            conn.execute(format!("INSERT INTO FloatTable (datetime, parameter, value, flag) VALUES ('2023-01-02 00:00:00', 'temperature', {value}, 0)"))
+                .unwrap();
+        }
+        drop(conn);
+
+        // a query only reads, it neither migrates the file nor reads the old columns
+        let datetime = parse_datetime("2023-01-02").unwrap();
+        let filter = QueryFilter::between(datetime, datetime);
+        let mut store = SqliteStore::new(root);
+        assert!(store.query(&filter, &mut |_| true).is_err());
+        let conn = sqlite::Connection::open(&db_path).unwrap();
+        assert!(recorded_version(&conn).unwrap().is_none());
+        drop(conn);
+
+        store.migrate().unwrap();
+        let conn = db_get(&db_path).unwrap();
+        assert_eq!(schema_version(&conn).unwrap(), SCHEMA_VERSION);
+        assert!(has_column(&conn, "TextTable", "raw").unwrap());
+        let mut index = false;
+        conn.iterate("PRAGMA index_list(FloatTable)", |pairs| {
+            index |= pairs
+                .iter()
+                .any(|&(_, value)| value == Some("FloatTable_parameter_datetime"));
+            true
+        })
+        .unwrap();
+        assert!(index);
+
+        let mut rows = Vec::new();
+        store
+            .query(&filter, &mut |record| {
+                rows.push(record);
+                true
+            })
+            .unwrap();
+        assert_eq!(rows.len(), 1);
+        assert!(rows[0].config_hash.is_none());
+        assert_eq!(rows[0].revision, 1);
+        assert_eq!(rows[0].value.to_string(), "2.5");
+
+        // files of a newer build are not touched
+        conn.execute("INSERT INTO schema_version (version) VALUES (99)").unwrap();
+        assert!(db_get(&db_path).is_err());
+    }
+
+    #[test]
+    fn conflict_policies() {
+        let datetime = parse_datetime("2023-01-02T00:00:00").unwrap();
+        let record = |value: f64, station: Option<&str>| Record {
+            datetime,
+            parameter: "temperature".to_string(),
+            station: station.map(|v| v.to_string()),
+            value: DataType::Float(value),
+            ..Default::default()
+        };
+        let filter = QueryFilter::between(datetime, datetime);
+
+        for (conflict, value, revision) in [
+            (ConflictPolicy::KeepFirst, "1", 0),
+            (ConflictPolicy::Replace, "3", 0),
+            (ConflictPolicy::KeepBoth, "3", 2),
+        ] {
+            let root = &temp_root(&format!("qc_database_case5_{conflict:?}"));
+
+            let mut store = SqliteStore::new(root);
+            store.set_conflict(conflict);
+            store.write(&[record(1.0, None), record(2.0, None)]).unwrap();
+            store.write(&[record(3.0, None), record(4.0, Some("st1"))]).unwrap();
+
+            let mut rows = Vec::new();
+            store
+                .query(&filter, &mut |record| {
+                    rows.push(record);
+                    true
+                })
+                .unwrap();
+            assert_eq!(rows.len(), 2, "{conflict:?}");
+            let row = rows.iter().find(|v| v.station.is_none()).unwrap();
+            assert_eq!(row.value.to_string(), value, "{conflict:?}");
+            assert_eq!(row.revision, revision, "{conflict:?}");
+
+            let conn = db_get(format!("{root}/20230102.db")).unwrap();
+            let mut statement = conn.prepare("SELECT count(*) AS n FROM FloatTable").unwrap();
+            statement.next().unwrap();
+            let stored = if conflict == ConflictPolicy::KeepBoth { 4 } else { 2 };
+            assert_eq!(statement.read::<i64, _>("n").unwrap(), stored, "{conflict:?}");
+        }
+    }
+
+    #[test]
+    fn partitioned_files_merged() {
+        let root = &temp_root("qc_database_case6");
+
+        let mut store = SqliteStore::new(root);
+        store.set_partitioning(
+            Partitioning::new(root, vec![PartitionKey::Station, PartitionKey::Monthly]).unwrap(),
+        );
+        let datetime = parse_datetime("2023-01-31T23:00:00").unwrap();
+        let records = (0..6)
+            .map(|i| Record {
+                datetime: datetime + Duration::hours(i),
+                parameter: "temperature".to_string(),
+                station: Some(format!("st{}", i % 2)),
+                value: DataType::Integer(i),
+                ..Default::default()
+            })
+            .collect::<Vec<_>>();
+        store.write(&records).unwrap();
+        assert!(Path::new(&format!("{root}/st0/202301.db")).exists());
+        assert!(Path::new(&format!("{root}/st1/202302.db")).exists());
+
+        // both stations and months, merged in time order
+        let mut filter = QueryFilter::between(datetime, datetime + Duration::days(1));
+        let mut values = Vec::new();
+        store
+            .query(&filter, &mut |record| {
+                values.push(record.value.to_string());
+                true
+            })
+            .unwrap();
+        assert_eq!(values, ["0", "1", "2", "3", "4", "5"]);
+
+        filter.station = Some("st1".to_string());
+        let mut values = Vec::new();
+        store
+            .query(&filter, &mut |record| {
+                values.push(record.value.to_string());
+                values.len() < 2
+            })
+            .unwrap();
+        assert_eq!(values, ["1", "3"]);
+    }
+
+    #[test]
+    fn flag_override_audited() {
+        let root = &temp_root("qc_database_case7");
+
+        let mut store = SqliteStore::with_batch(root, 100, StdDuration::from_secs(60));
+        store.set_conflict(ConflictPolicy::KeepBoth);
+        let datetime = parse_datetime("2023-01-02T00:00:00").unwrap();
+        let record = |flag: QCFlag| Record {
+            datetime,
+            parameter: "temperature".to_string(),
+            value: DataType::Float(10.0),
+            flag,
+            ..Default::default()
+        };
+        // still buffered, and two revisions of which only the latest changes
+        store.write(&[record(QCFlag::L0_Warn), record(QCFlag::L0_Warn)]).unwrap();
+
+        let filter = QueryFilter {
+            parameters: vec!["temperature".to_string()],
+            ..QueryFilter::between(datetime, datetime)
+        };
+        let change = FlagOverride {
+            set: QCFlag::L0_Error,
+            clear: QCFlag::L0_Warn,
+            user: "tech".to_string(),
+            reason: "sensor swapped".to_string(),
+        };
+        assert_eq!(store.override_flags(&filter, &change).unwrap(), 1);
+        // nothing left to change
+        assert_eq!(store.override_flags(&filter, &change).unwrap(), 0);
+
+        let mut flags = Vec::new();
+        store
+            .query(&filter, &mut |record| {
+                flags.push(record.flag.bits());
+                true
+            })
+            .unwrap();
+        assert_eq!(flags, [QCFlag::L0_Error.bits()]);
+
+        let conn = db_get(format!("{root}/20230102.db")).unwrap();
+        let mut statement = conn
+            .prepare("SELECT user, reason, revision, old_flag, new_flag FROM flag_audit")
+            .unwrap();
+        assert_eq!(statement.next().unwrap(), State::Row);
+        assert_eq!(statement.read::<String, _>("user").unwrap(), "tech");
+        assert_eq!(statement.read::<String, _>("reason").unwrap(), "sensor swapped");
+        assert_eq!(statement.read::<i64, _>("revision").unwrap(), 1);
+        assert_eq!(statement.read::<i64, _>("old_flag").unwrap(), QCFlag::L0_Warn.bits() as i64);
+        assert_eq!(statement.next().unwrap(), State::Done);
+    }
+
+    #[test]
+    fn failing_file_is_rejected() {
+        let root = &temp_root("qc_database_rejected");
+        // a directory where the file goes, it can't be opened
+        std::fs::create_dir_all(format!("{root}/20230102.db")).unwrap();
+
+        let mut store = SqliteStore::new(root);
+        let record = Record {
+            datetime: parse_datetime("2023-01-02T00:00:00").unwrap(),
+            parameter: "humidity".to_string(),
+            value: DataType::Float(50.0),
+            ..Default::default()
+        };
+        for _ in 1..MAX_ATTEMPTS {
+            assert!(store.write(std::slice::from_ref(&record)).is_err());
+        }
+        assert_eq!(store.buffer.len(), MAX_ATTEMPTS - 1);
+        let e = store.write(&[record]).unwrap_err();
+        assert!(e.to_string().contains("rejected"));
+        assert!(store.buffer.is_empty());
+
+        let rejected = CsvStore::new(format!("{root}/rejected"));
+        let filter = QueryFilter::between(
+            parse_datetime("2023-01-02").unwrap(),
+            parse_datetime("2023-01-03").unwrap(),
+        );
+        let mut count = 0;
+        rejected
+            .query(&filter, &mut |_| {
+                count += 1;
+                true
+            })
+            .unwrap();
+        assert_eq!(count, MAX_ATTEMPTS);
+    }
+
+    #[test]
+    fn least_recently_used_connection_closed() {
+        let root = &temp_root("qc_database_connections");
+
+        let mut store = SqliteStore::new(root);
+        for day in ["01", "02", "03", "04", "01", "05"] {
+            store
+                .write(&[Record {
+                    datetime: parse_datetime(&format!("2023-01-{day}")).unwrap(),
+                    parameter: "humidity".to_string(),
+                    value: DataType::Float(50.0),
+                    ..Default::default()
+                }])
+                .unwrap();
+        }
+        let open = store
+            .connections
+            .iter()
+            .map(|(path, _)| &path[path.len() - 11..])
+            .collect::<Vec<_>>();
+        assert_eq!(open, ["20230103.db", "20230104.db", "20230101.db", "20230105.db"]);
+    }
+}
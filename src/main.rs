@@ -1,47 +1,39 @@
+#![allow(special_module_name)]
 mod lib;
 mod utils;
 
-use std::collections::HashMap;
+use std::{collections::HashMap, io::Write, time::Duration};
 
 use clap::Parser;
 use lib::ERROR;
-use tonic::{transport::Server, Request, Response, Status};
+use tokio::{net::UnixListener, sync::mpsc};
+use tokio_stream::wrappers::{ReceiverStream, UnixListenerStream};
+use tonic::transport::Server;
 
 use crate::{
-    lib::qc_worker::QCworker,
-    utils::cli::{Command::*, Operations},
+    lib::{
+        config_parser::{DaemonConfig, QCConfig},
+        daemon::{shutdown_signal, AdminDaemon, HealthDaemon, QcDaemon},
+        health::health_server::HealthServer,
+        data_parser::{parse_datetime, with_protocol},
+        export::{self, ExportFormat, Table},
+        qc_worker::{config_path, reprocess, stored_lines, QCFlag, QCworker},
+        storage::{Backend, ConflictPolicy, QueryFilter},
+    },
+    utils::{
+        cli::{AdminCommand, Command::*, FlagCommand, Operations, ReprocessSource},
+        transport::{BindAddr, RequireToken},
+    },
 };
 
 use lib::qc::{
-    qc_server::{Qc, QcServer},
-    qc_client::QcClient,
-    SendRequest, SendResponse,
+    admin_server::AdminServer,
+    qc_server::QcServer,
+    stream_response, BatchRequest, ListMaintenanceRequest, ListParametersRequest,
+    MaintenanceWindow, OverrideRequest, ParameterRequest, QueryRequest, RemoveMaintenanceRequest, SendRequest,
+    StreamSummary, SubscribeRequest,
 };
 
-#[derive(Default)]
-pub struct QcDaemon {}
-
-#[tonic::async_trait]
-impl Qc for QcDaemon {
-    async fn send(&self, request: Request<SendRequest>) -> Result<Response<SendResponse>, Status> {
-        let mut qc = QCworker::new(HashMap::new());
-
-        let raw_data = if let Some(fidx) = request.get_ref().protocol {
-            format!("F{fidx},{}", request.get_ref().payload)
-        } else {
-            request.get_ref().payload.to_string()
-        };
-
-        qc.handler(&raw_data);
-        qc.set_database("database");
-        qc.save().unwrap();
-
-        Ok(Response::new(SendResponse {
-            status: format!("Ok"),
-        }))
-    }
-}
-
 #[tokio::main]
 async fn main() -> Result<(), ERROR> {
     let oper = Operations::parse();
@@ -49,46 +41,476 @@ async fn main() -> Result<(), ERROR> {
     match oper.command {
         Daemon(opts) => {
             // println!("daemon: {:?}", opts);
-            let addr = format!("[::1]:{}", opts.port).parse().unwrap();
+            let bind = opts.bind_addr()?;
+            let auth = RequireToken::new(opts.token()?);
+
+            let config = DaemonConfig::load(&opts.config)?;
+            let srv = QcDaemon::new(&config)?;
+            let worker = srv.worker();
+            let restored = worker.lock().unwrap().restore_state(&opts.state, config.state.max_age)?;
+            if !restored.is_empty() {
+                println!("Worker state restored from {}: {restored:?}", opts.state);
+            }
+            let admin = AdminDaemon::new(srv.worker());
+            let health = HealthDaemon::default();
+            let health_status = health.clone();
+            let daemon = srv.clone();
+
+            // the store writes out its batch once flush_interval passed, also when no lines arrive
+            let saver = srv.worker();
+            let interval = Duration::from_secs(config.storage.flush_interval.max(1));
+            let saver_task = tokio::spawn(async move {
+                let mut ticker = tokio::time::interval(interval);
+                loop {
+                    ticker.tick().await;
+                    let saver = saver.clone();
+                    let result =
+                        tokio::task::spawn_blocking(move || saver.lock().unwrap().save().map_err(|e| e.to_string()))
+                            .await;
+                    if let Ok(Err(e)) = result {
+                        eprintln!("Failed to save results: {e}");
+                    }
+                }
+            });
+
+            // gap records are made while no lines arrive
+            let gaps = srv.clone();
+            let gap_task = tokio::spawn(async move {
+                let mut ticker = tokio::time::interval(Duration::from_secs(1));
+                loop {
+                    ticker.tick().await;
+                    let gaps = gaps.clone();
+                    let result =
+                        tokio::task::spawn_blocking(move || gaps.check_gaps().map_err(|e| e.to_string())).await;
+                    if let Ok(Err(e)) = result {
+                        eprintln!("Failed to save gap records: {e}");
+                    }
+                }
+            });
+
+            // a crash loses at most save_interval of worker state
+            let snapshot = srv.worker();
+            let state_path = opts.state.clone();
+            let interval = Duration::from_secs(config.state.save_interval.max(1));
+            let snapshot_task = tokio::spawn(async move {
+                let mut ticker = tokio::time::interval(interval);
+                loop {
+                    ticker.tick().await;
+                    let (snapshot, state_path) = (snapshot.clone(), state_path.clone());
+                    let result = tokio::task::spawn_blocking(move || {
+                        snapshot.lock().unwrap().save_state(&state_path).map_err(|e| e.to_string())
+                    })
+                    .await;
+                    if let Ok(Err(e)) = result {
+                        eprintln!("Failed to save worker state: {e}");
+                    }
+                }
+            });
+
+            let mut builder = Server::builder();
+            if let Some(tls) = opts.tls_config()? {
+                builder = builder.tls_config(tls)?;
+            }
+            // health stays open for probes
+            let router = builder
+                .add_service(HealthServer::new(health))
+                .add_service(QcServer::with_interceptor(srv, auth.clone()))
+                .add_service(AdminServer::with_interceptor(admin, auth));
+            let signal = async move {
+                shutdown_signal().await;
+                println!("Shutting down, waiting for running requests");
+                health_status.set_serving(false);
+                daemon.close();
+            };
 
-            let srv = QcDaemon::default();
+            match bind {
+                BindAddr::Tcp(addr) => {
+                    router.serve_with_shutdown(addr, signal).await?;
+                }
+                BindAddr::Unix(path) => {
+                    // left over by a daemon which did not stop cleanly
+                    if path.exists() {
+                        std::fs::remove_file(&path)?;
+                    }
+                    let incoming = UnixListenerStream::new(UnixListener::bind(&path)?);
+                    router.serve_with_incoming_shutdown(incoming, signal).await?;
+                    std::fs::remove_file(&path)?;
+                }
+            }
 
-            Server::builder()
-                .add_service(QcServer::new(srv))
-                .serve(addr)
-                .await?;
+            // requests are drained, the timers would still add gap records after the last flush
+            for task in [saver_task, gap_task, snapshot_task] {
+                task.abort();
+                let _ = task.await;
+            }
+            let mut qc = worker.lock().unwrap();
+            // the state is saved also when the results can't be written
+            let flushed = qc.flush();
+            qc.save_state(&opts.state)?;
+            println!("Worker state saved to {}", opts.state);
+            flushed?;
         }
 
         Qc(opts) => {
             // println!("qc: {:?}", opts);
 
-            if let Some(ip) = opts.ip {
-                let addr = format!("http://{addr}:{port}", addr = ip, port = opts.port);
-                let mut client = QcClient::connect(addr).await?;
-                let request = tonic::Request::new(
-                    SendRequest {
-                        protocol: opts.protocol,
-                        payload: opts.data
+            if opts.client.is_remote() {
+                let mut client = opts.client.qc_client().await?;
+
+                if let Some(path) = opts.file {
+                    let content = std::fs::read_to_string(path)?;
+                    let lines = content
+                        .lines()
+                        .map(|line| line.trim())
+                        .filter(|line| !line.is_empty())
+                        .collect::<Vec<_>>();
+
+                    let mut total = StreamSummary::default();
+                    for (n, chunk) in lines.chunks(opts.batch_size.max(1)).enumerate() {
+                        let request = tonic::Request::new(BatchRequest {
+                            payloads: chunk.iter().map(|line| line.to_string()).collect(),
+                            protocol: opts.protocol,
+                            station: opts.station.clone(),
+                        });
+                        let response = client.send_batch(request).await?.into_inner();
+
+                        for line in response.results.iter().filter(|line| line.status != "Ok") {
+                            let lineno = n * opts.batch_size.max(1) + line.index as usize;
+                            println!("line {}: {}", lineno + 1, line.status);
+                        }
+                        if let Some(summary) = response.summary {
+                            total.received += summary.received;
+                            total.succeeded += summary.succeeded;
+                            total.failed += summary.failed;
+                            total.flagged += summary.flagged;
+                        }
                     }
-                );
+                    println!("{total:?}");
+                } else if opts.stream {
+                    let (tx, rx) = mpsc::channel(64);
+                    let protocol = opts.protocol;
+                    let station = opts.station.clone();
+                    std::thread::spawn(move || {
+                        for line in std::io::stdin().lines() {
+                            let line = line.unwrap();
+                            if line.trim().is_empty() {
+                                continue;
+                            }
+                            let request = SendRequest {
+                                protocol,
+                                payload: line.trim().to_string(),
+                                station: station.clone(),
+                            };
+                            if tx.blocking_send(request).is_err() {
+                                break;
+                            }
+                        }
+                    });
+
+                    let mut response = client.stream(ReceiverStream::new(rx)).await?.into_inner();
+                    while let Some(msg) = response.message().await? {
+                        match msg.result {
+                            Some(stream_response::Result::Line(line)) => println!("{line:?}"),
+                            Some(stream_response::Result::Summary(summary)) => println!("{summary:?}"),
+                            None => {}
+                        }
+                    }
+                } else {
+                    let request = tonic::Request::new(
+                        SendRequest {
+                            protocol: opts.protocol,
+                            payload: opts.data.unwrap(),
+                            station: opts.station,
+                        }
+                    );
 
-                let response = client.send(request).await?.into_inner();
+                    let response = client.send(request).await?.into_inner();
+                    println!("{}", response.status);
+                }
             } else {
                 let mut qc = QCworker::new(HashMap::new());
-                let raw_data = if let Some(fidx) = opts.protocol {
-                    format!("F{fidx},{}", opts.data)
-                } else {
-                    opts.data
-                };
+                qc.set_station(opts.station);
+                let raw_data = with_protocol(opts.protocol, &opts.data.unwrap());
     
-                qc.handler(&raw_data);
+                qc.handler(&raw_data)?;
                 qc.show_report();
                 if opts.save {
                     qc.set_database("database");
-                    qc.save().unwrap();
+                    qc.save()?;
                 }
             }
         }
+
+        Query(opts) => {
+            let mut client = opts.client.qc_client().await?;
+            let request = tonic::Request::new(QueryRequest {
+                parameters: opts.parameters,
+                start: opts.start,
+                end: opts.end,
+                station: opts.station,
+                flag_mask: opts.flag_mask,
+            });
+
+            let mut response = client.query(request).await?.into_inner();
+            println!("datetime,parameter,station,value,flag,revision");
+            while let Some(row) = response.message().await? {
+                println!(
+                    "{},{},{},{},{},{}",
+                    row.datetime, row.parameter, row.station, row.value, row.flag, row.revision
+                );
+            }
+        }
+
+        Subscribe(opts) => {
+            let mut client = opts.client.qc_client().await?;
+            let request = tonic::Request::new(SubscribeRequest {
+                parameters: opts.parameters,
+                station: opts.station,
+                flag_mask: opts.flag_mask,
+            });
+
+            let mut response = client.subscribe(request).await?.into_inner();
+            while let Some(row) = response.message().await? {
+                println!(
+                    "{},{},{},{},{}",
+                    row.datetime, row.parameter, row.station, row.value, row.flag
+                );
+            }
+        }
+
+        Admin(opts) => {
+            let mut client = opts.client.admin_client().await?;
+            match opts.command {
+                AdminCommand::Reload(target) => {
+                    let request = tonic::Request::new(ParameterRequest {
+                        parameter: target.parameter,
+                        station: target.station,
+                    });
+                    let response = client.reload_config(request).await?.into_inner();
+                    println!("reloaded: {:?}", response.parameters);
+                }
+                AdminCommand::List => {
+                    let request = tonic::Request::new(ListParametersRequest {});
+                    let response = client.list_parameters(request).await?.into_inner();
+                    println!("parameter,configured,running");
+                    for info in response.parameters {
+                        println!("{},{},{}", info.name, info.configured, info.running);
+                    }
+                }
+                AdminCommand::State(target) => {
+                    let request = tonic::Request::new(ParameterRequest {
+                        parameter: target.parameter,
+                        station: target.station,
+                    });
+                    let response = client.get_worker_state(request).await?.into_inner();
+                    for state in response.states {
+                        println!("{state:#?}");
+                    }
+                }
+                AdminCommand::Reset(target) => {
+                    let request = tonic::Request::new(ParameterRequest {
+                        parameter: target.parameter,
+                        station: target.station,
+                    });
+                    let response = client.reset_state(request).await?.into_inner();
+                    println!("reset: {:?}", response.parameters);
+                }
+                AdminCommand::Stop(target) => {
+                    let request = tonic::Request::new(ParameterRequest {
+                        parameter: target.parameter,
+                        station: target.station,
+                    });
+                    let response = client.stop_parameter(request).await?.into_inner();
+                    println!("stopped: {:?}", response.parameters);
+                }
+                AdminCommand::Start(target) => {
+                    let request = tonic::Request::new(ParameterRequest {
+                        parameter: target.parameter,
+                        station: target.station,
+                    });
+                    let response = client.start_parameter(request).await?.into_inner();
+                    println!("started: {:?}", response.parameters);
+                }
+                AdminCommand::AddMaintenance(window) => {
+                    let request = tonic::Request::new(MaintenanceWindow {
+                        start: window.start,
+                        end: window.end,
+                        station: window.station,
+                        parameters: window.parameters,
+                        reason: window.reason,
+                    });
+                    let response = client.add_maintenance(request).await?.into_inner();
+                    println!("maintenance windows: {}", response.windows.len());
+                }
+                AdminCommand::ListMaintenance => {
+                    let request = tonic::Request::new(ListMaintenanceRequest {});
+                    let response = client.list_maintenance(request).await?.into_inner();
+                    println!("index,start,end,station,parameters,reason");
+                    for (index, window) in response.windows.into_iter().enumerate() {
+                        println!(
+                            "{index},{},{},{},{},{}",
+                            window.start,
+                            window.end,
+                            window.station.unwrap_or_default(),
+                            window.parameters.join(";"),
+                            window.reason
+                        );
+                    }
+                }
+                AdminCommand::RemoveMaintenance { index } => {
+                    let request = tonic::Request::new(RemoveMaintenanceRequest { index });
+                    let response = client.remove_maintenance(request).await?.into_inner();
+                    println!("maintenance windows: {}", response.windows.len());
+                }
+                AdminCommand::Override(change) => {
+                    let bits = |value: Option<String>| -> Result<u64, ERROR> {
+                        Ok(value.map(|v| v.parse::<QCFlag>()).transpose()?.unwrap_or_default().bits())
+                    };
+                    let request = tonic::Request::new(OverrideRequest {
+                        parameters: change.parameters,
+                        start: change.start,
+                        end: change.end,
+                        station: change.station,
+                        set: bits(change.set)?,
+                        clear: bits(change.clear)?,
+                        user: change
+                            .user
+                            .or_else(|| std::env::var("USER").ok())
+                            .unwrap_or_default(),
+                        reason: change.reason,
+                    });
+                    let response = client.override_flags(request).await?.into_inner();
+                    println!("changed: {}", response.changed);
+                }
+            }
+        }
+
+        Reprocess(opts) => {
+            let config = DaemonConfig::load(&opts.config)?;
+            let mut storage = config.storage.clone();
+            // the file backends only append, values would be stored twice
+            if storage.backend != Backend::Sqlite {
+                return Err("reprocess needs the sqlite backend".into());
+            }
+            storage.on_conflict = if opts.keep_both {
+                ConflictPolicy::KeepBoth
+            } else {
+                ConflictPolicy::Replace
+            };
+            let mut store = storage.open()?;
+
+            let filter = QueryFilter {
+                parameters: vec![opts.parameter.clone()],
+                start: parse_datetime(&opts.start)?,
+                end: parse_datetime(&opts.end)?,
+                station: opts.station.clone(),
+                flag_mask: None,
+            };
+            // every parameter, the values of a line are checked together
+            let mut stored = Vec::new();
+            store.query(&QueryFilter { parameters: Vec::new(), ..filter.clone() }, &mut |record| {
+                stored.push(record);
+                true
+            })?;
+            let previous = stored
+                .iter()
+                .filter(|record| record.parameter == opts.parameter)
+                .map(|record| ((record.datetime, record.station.clone()), record.flag.bits()))
+                .collect::<HashMap<_, _>>();
+
+            let lines = match opts.from {
+                ReprocessSource::Stored => stored_lines(&opts.parameter, stored),
+                ReprocessSource::Archive => {
+                    let archive = config
+                        .archive
+                        .as_ref()
+                        .ok_or("No [archive] in the daemon config")?
+                        .open()?;
+                    // the archive is by receive time and lines arrive after their values, up to
+                    // `max_delay` later; a day earlier for stations with their clock ahead
+                    let received = (
+                        filter.start - chrono::Duration::days(1),
+                        filter.end + chrono::Duration::hours(opts.max_delay as i64),
+                    );
+                    // only the lines of the filter are kept while reading
+                    let mut qc = QCworker::new(HashMap::new());
+                    let mut lines = Vec::new();
+                    archive.query(received.0, received.1, &mut |raw| {
+                        lines.extend(qc.archived_line(&raw, &filter));
+                        true
+                    })?;
+                    lines
+                }
+            };
+
+            let records = reprocess(&opts.parameter, lines, &config.maintenance)?
+                .into_iter()
+                .filter(|record| filter.matches(record))
+                .collect::<Vec<_>>();
+            let changed = records
+                .iter()
+                .filter(|record| {
+                    previous.get(&(record.datetime, record.station.clone()))
+                        != Some(&record.flag.bits())
+                })
+                .count();
+            if !opts.dry_run {
+                store.write(&records)?;
+                store.flush()?;
+            }
+            println!("reprocessed: {}, flags changed: {changed}", records.len());
+        }
+
+        Export(opts) => {
+            let config = DaemonConfig::load(&opts.config)?;
+            let store = config.storage.open()?;
+            let filter = QueryFilter {
+                parameters: opts.parameters,
+                start: parse_datetime(&opts.start)?,
+                end: parse_datetime(&opts.end)?,
+                station: opts.station,
+                flag_mask: None,
+            };
+            let mut records = Vec::new();
+            store.query(&filter, &mut |record| {
+                records.push(record);
+                true
+            })?;
+            let table = Table::new(
+                records,
+                &export::ExportOptions {
+                    errors: opts.errors,
+                    decode_flags: opts.decode_flags,
+                    pivot: opts.pivot,
+                },
+            );
+
+            let output: Box<dyn Write> = match (&opts.output, opts.format) {
+                (Some(path), ExportFormat::Parquet) => return table.write_parquet(path),
+                (None, ExportFormat::Parquet) => return Err("parquet needs --output".into()),
+                (Some(path), _) => Box::new(std::fs::File::create(path)?),
+                (None, _) => Box::new(std::io::stdout().lock()),
+            };
+            if opts.format == ExportFormat::Json {
+                table.write_json(output)?;
+            } else {
+                table.write_csv(output)?;
+            }
+        }
+
+        Flag(opts) => match opts.command {
+            FlagCommand::Explain(target) => {
+                let flag = target.value.parse::<QCFlag>()?;
+                let config = match &target.parameter {
+                    Some(parameter) => Some(QCConfig::load(&config_path(parameter))?),
+                    None => None,
+                };
+                println!("{} = {flag}", flag.bits());
+                for term in flag.explain(config.as_ref()) {
+                    println!("{term}");
+                }
+            }
+        },
     }
 
     Ok(())
@@ -96,7 +518,6 @@ async fn main() -> Result<(), ERROR> {
 
 #[cfg(test)]
 mod test {
-    use super::*;
     #[test]
     fn case1() {}
 }
@@ -1,6 +1,7 @@
 
-use chrono::NaiveDateTime;
-use clap::Parser;
+use clap::{ArgGroup, Parser, ValueEnum};
+
+use crate::lib::export::{ErrorValues, ExportFormat};
 
 #[derive(Debug,Parser)]
 pub struct Operations {
@@ -12,25 +13,326 @@ pub struct Operations {
 pub enum Command {
     Daemon(DaemonOptions),
     Qc(QcOptions),
+    /// Read stored QC values from the daemon
+    Query(QueryOptions),
+    /// Follow QC results as the daemon processes them
+    Subscribe(SubscribeOptions),
+    /// Manage a running daemon
+    Admin(AdminOptions),
+    /// Run QC again over stored values with the current config
+    Reprocess(ReprocessOptions),
+    /// Write stored QC values to a csv, json or parquet file
+    Export(ExportOptions),
+    /// Work with stored flag values
+    Flag(FlagOptions),
 }
 
 #[derive(Debug, Parser)]
 pub struct DaemonOptions {
     #[clap(long, default_value_t = 50500)]
     pub port: usize,
+    /// Listen on `host:port` or `unix:/path/to/socket` instead of [::1]:{port}
+    #[clap(long)]
+    pub bind: Option<String>,
+    /// Server certificate (PEM), enables TLS together with --tls-key
+    #[clap(long)]
+    pub tls_cert: Option<String>,
+    /// Server private key (PEM)
+    #[clap(long)]
+    pub tls_key: Option<String>,
+    /// Require client certificates signed by this CA (PEM)
+    #[clap(long, requires = "tls_cert")]
+    pub tls_client_ca: Option<String>,
+    /// Require `authorization: Bearer {token}` on QC and Admin requests
+    #[clap(long)]
+    pub token: Option<String>,
+    /// Read the token from a file instead
+    #[clap(long, conflicts_with = "token")]
+    pub token_file: Option<String>,
+    /// Daemon settings, e.g. the storage backend
+    #[clap(long, default_value = "config/daemon.toml")]
+    pub config: String,
+    /// Where the worker state is written, restored from at startup
+    #[clap(long, default_value = "database/worker_state.toml")]
+    pub state: String,
 }
 
 #[derive(Debug, Parser)]
+#[clap(group(ArgGroup::new("remote").args(["ip", "socket"]).multiple(true)))]
 pub struct QcOptions {
     #[clap(short, long)]
     pub protocol: Option<u32>,
-    #[clap(short, long)]
-    pub data: String,
+    #[clap(short, long, required_unless_present_any = ["stream", "file"])]
+    pub data: Option<String>,
+    /// Read lines from stdin and push them to the daemon over one stream
+    #[clap(long, default_value_t = false, requires = "remote")]
+    pub stream: bool,
+    /// Upload every line of the file to the daemon with SendBatch
+    #[clap(long, requires = "remote", conflicts_with = "stream")]
+    pub file: Option<String>,
+    /// Number of lines per SendBatch request
+    #[clap(long, default_value_t = 1000)]
+    pub batch_size: usize,
+    #[clap(long)]
+    pub station: Option<String>,
     #[clap(short, long, default_value_t = false)]
     pub save: bool,
 
+    /// Run QC locally unless --ip or --socket is given
+    #[clap(flatten)]
+    pub client: ClientOptions,
+}
+
+#[derive(Debug, Parser)]
+pub struct ClientOptions {
+    /// Daemon address, default [::1]
     #[clap(long)]
     pub ip: Option<String>,
     #[clap(long, default_value_t = 50500)]
     pub port: usize,
+    /// Connect over this unix domain socket instead of ip:port
+    #[clap(long, conflicts_with = "ip")]
+    pub socket: Option<String>,
+    /// Use TLS, implied by --tls-ca and --tls-cert
+    #[clap(long, default_value_t = false)]
+    pub tls: bool,
+    /// CA certificate (PEM) to verify the daemon
+    #[clap(long)]
+    pub tls_ca: Option<String>,
+    /// Client certificate (PEM), together with --tls-key
+    #[clap(long)]
+    pub tls_cert: Option<String>,
+    /// Client private key (PEM)
+    #[clap(long)]
+    pub tls_key: Option<String>,
+    /// Name to verify the daemon certificate against, when it differs from --ip
+    #[clap(long)]
+    pub tls_domain: Option<String>,
+    /// Sent as `authorization: Bearer {token}`
+    #[clap(long)]
+    pub token: Option<String>,
+    /// Read the token from a file instead
+    #[clap(long, conflicts_with = "token")]
+    pub token_file: Option<String>,
+}
+
+impl ClientOptions {
+    pub fn is_remote(&self) -> bool {
+        self.ip.is_some() || self.socket.is_some()
+    }
+}
+
+#[derive(Debug, Parser)]
+pub struct QueryOptions {
+    /// Parameters to read, all parameters if not given
+    #[clap(short, long = "parameter")]
+    pub parameters: Vec<String>,
+    /// Start datetime (inclusive), e.g. 2023-01-02T00:00:00
+    #[clap(long)]
+    pub start: String,
+    /// End datetime (inclusive), e.g. 2023-01-02T23:59:59
+    #[clap(long)]
+    pub end: String,
+    #[clap(long)]
+    pub station: Option<String>,
+    /// Only rows with any of these flag bits set
+    #[clap(long)]
+    pub flag_mask: Option<u64>,
+
+    #[clap(flatten)]
+    pub client: ClientOptions,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum ReprocessSource {
+    /// values in the store
+    Stored,
+    /// lines of the raw archive with values between --start and --end, parsed again
+    Archive,
+}
+
+#[derive(Debug, Parser)]
+pub struct ReprocessOptions {
+    #[clap(short, long)]
+    pub parameter: String,
+    /// Start datetime (inclusive), e.g. 2023-01-02T00:00:00
+    #[clap(long)]
+    pub start: String,
+    /// End datetime (inclusive), e.g. 2023-01-02T23:59:59
+    #[clap(long)]
+    pub end: String,
+    #[clap(long)]
+    pub station: Option<String>,
+    #[clap(long, value_enum, default_value_t = ReprocessSource::Stored)]
+    pub from: ReprocessSource,
+    /// With `--from archive`, lines arrive at most this many hours after their values
+    #[clap(long, default_value_t = 24)]
+    pub max_delay: u64,
+    /// Store the new flags as a new revision instead of overwriting
+    #[clap(long, default_value_t = false)]
+    pub keep_both: bool,
+    /// Only report how many flags would change
+    #[clap(long, default_value_t = false)]
+    pub dry_run: bool,
+    /// Daemon settings, for the storage and the raw archive
+    #[clap(long, default_value = "config/daemon.toml")]
+    pub config: String,
+}
+
+#[derive(Debug, Parser)]
+pub struct ExportOptions {
+    /// Parameters to export, all parameters if not given
+    #[clap(short, long = "parameter")]
+    pub parameters: Vec<String>,
+    /// Start datetime (inclusive), e.g. 2023-01-02T00:00:00
+    #[clap(long)]
+    pub start: String,
+    /// End datetime (inclusive), e.g. 2023-01-02T23:59:59
+    #[clap(long)]
+    pub end: String,
+    #[clap(long)]
+    pub station: Option<String>,
+    #[clap(long, value_enum, default_value_t = ExportFormat::Csv)]
+    pub format: ExportFormat,
+    /// Write to this file instead of stdout, required for parquet
+    #[clap(short, long)]
+    pub output: Option<String>,
+    /// Values with an error flag
+    #[clap(long, value_enum, default_value_t = ErrorValues::Keep)]
+    pub errors: ErrorValues,
+    /// Flag as the levels of its warnings and errors instead of its bits
+    #[clap(long, default_value_t = false)]
+    pub decode_flags: bool,
+    /// One row per datetime and station, with the columns of every parameter
+    #[clap(long, default_value_t = false)]
+    pub pivot: bool,
+    /// Daemon settings, for the storage
+    #[clap(long, default_value = "config/daemon.toml")]
+    pub config: String,
+}
+
+#[derive(Debug, Parser)]
+pub struct SubscribeOptions {
+    /// Parameters to follow, all parameters if not given
+    #[clap(short, long = "parameter")]
+    pub parameters: Vec<String>,
+    #[clap(long)]
+    pub station: Option<String>,
+    /// Only results with any of these flag bits set
+    #[clap(long)]
+    pub flag_mask: Option<u64>,
+
+    #[clap(flatten)]
+    pub client: ClientOptions,
+}
+
+#[derive(Debug, Parser)]
+pub struct AdminOptions {
+    #[clap(subcommand)]
+    pub command: AdminCommand,
+
+    #[clap(flatten)]
+    pub client: ClientOptions,
+}
+
+#[derive(Debug, Parser)]
+pub enum AdminCommand {
+    /// Re-read parameter configs and modules
+    Reload(ParameterOptions),
+    /// List configured and running parameters
+    List,
+    /// Show the worker state of parameters
+    State(ParameterOptions),
+    /// Drop the QC state of parameters
+    Reset(ParameterOptions),
+    /// Stop QC of parameters for maintenance, values are stored flagged as maintenance
+    Stop(ParameterOptions),
+    /// Resume QC of stopped parameters
+    Start(ParameterOptions),
+    /// Flag values of a period as maintenance, kept across restarts with the worker state
+    AddMaintenance(MaintenanceOptions),
+    /// Show the maintenance periods
+    ListMaintenance,
+    /// Drop a maintenance period by its index in list-maintenance
+    RemoveMaintenance {
+        index: u32,
+    },
+    /// Set and clear flag bits of stored rows
+    Override(OverrideOptions),
+}
+
+#[derive(Debug, Parser)]
+pub struct FlagOptions {
+    #[clap(subcommand)]
+    pub command: FlagCommand,
+}
+
+#[derive(Debug, Parser)]
+pub enum FlagCommand {
+    /// Tell which levels warned or failed for a flag value
+    Explain(ExplainOptions),
+}
+
+#[derive(Debug, Parser)]
+pub struct ExplainOptions {
+    /// Flag as stored, e.g. 4294967297, or as terms like "L0_Warn | L0_Error"
+    pub value: String,
+    /// List the modules of each level from the config of this parameter
+    #[clap(short, long)]
+    pub parameter: Option<String>,
+}
+
+#[derive(Debug, Parser)]
+pub struct MaintenanceOptions {
+    /// Start datetime (inclusive), e.g. 2023-01-02T08:00:00
+    #[clap(long)]
+    pub start: String,
+    /// End datetime (inclusive)
+    #[clap(long)]
+    pub end: String,
+    /// Every station if not given
+    #[clap(long)]
+    pub station: Option<String>,
+    /// Every parameter if not given
+    #[clap(short, long = "parameter")]
+    pub parameters: Vec<String>,
+    #[clap(long, default_value = "")]
+    pub reason: String,
+}
+
+#[derive(Debug, Parser)]
+pub struct OverrideOptions {
+    /// Every parameter if not given
+    #[clap(short, long = "parameter")]
+    pub parameters: Vec<String>,
+    /// Start datetime (inclusive)
+    #[clap(long)]
+    pub start: String,
+    /// End datetime (inclusive)
+    #[clap(long)]
+    pub end: String,
+    #[clap(long)]
+    pub station: Option<String>,
+    /// Bits to set, e.g. 4294967296 or "L0_Error"
+    #[clap(long)]
+    pub set: Option<String>,
+    /// Bits to clear, after setting
+    #[clap(long)]
+    pub clear: Option<String>,
+    /// Who changes the flags, $USER if not given
+    #[clap(long)]
+    pub user: Option<String>,
+    /// Why the flags are changed
+    #[clap(long)]
+    pub reason: String,
+}
+
+#[derive(Debug, Parser)]
+pub struct ParameterOptions {
+    /// All parameters if not given
+    #[clap(short, long)]
+    pub parameter: Option<String>,
+    /// Every station if not given
+    #[clap(long)]
+    pub station: Option<String>,
 }
\ No newline at end of file
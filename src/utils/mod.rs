@@ -1 +1,2 @@
-pub mod cli;
\ No newline at end of file
+pub mod cli;
+pub mod transport;
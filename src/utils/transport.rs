@@ -0,0 +1,224 @@
+use std::{net::SocketAddr, path::PathBuf};
+
+use tokio::net::UnixStream;
+use tonic::{
+    metadata::{Ascii, MetadataValue},
+    service::{interceptor::InterceptedService, Interceptor},
+    transport::{
+        Certificate, Channel, ClientTlsConfig, Endpoint, Identity, ServerTlsConfig, Uri,
+    },
+    Request, Status,
+};
+
+use crate::lib::{
+    qc::{admin_client::AdminClient, qc_client::QcClient},
+    ERROR,
+};
+
+use super::cli::{ClientOptions, DaemonOptions};
+
+const AUTHORIZATION: &str = "authorization";
+
+/// Where the daemon listens, `--bind host:port` or `--bind unix:/path/to/socket`.
+#[derive(Debug, Clone)]
+pub enum BindAddr {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl BindAddr {
+    pub fn parse(s: &str) -> Result<Self, ERROR> {
+        if let Some(path) = s.strip_prefix("unix:") {
+            Ok(BindAddr::Unix(PathBuf::from(path)))
+        } else {
+            Ok(BindAddr::Tcp(
+                s.parse().map_err(|_| format!("Invalid bind address: {s}"))?,
+            ))
+        }
+    }
+}
+
+impl DaemonOptions {
+    pub fn bind_addr(&self) -> Result<BindAddr, ERROR> {
+        match &self.bind {
+            Some(v) => BindAddr::parse(v),
+            None => BindAddr::parse(&format!("[::1]:{}", self.port)),
+        }
+    }
+
+    /// TLS config from `--tls-cert` and `--tls-key`, clients must present a certificate
+    /// signed by `--tls-client-ca` when given.
+    pub fn tls_config(&self) -> Result<Option<ServerTlsConfig>, ERROR> {
+        let (cert, key) = match (&self.tls_cert, &self.tls_key) {
+            (Some(cert), Some(key)) => (std::fs::read(cert)?, std::fs::read(key)?),
+            (None, None) => return Ok(None),
+            _ => return Err("--tls-cert and --tls-key must be given together".into()),
+        };
+
+        let mut tls = ServerTlsConfig::new().identity(Identity::from_pem(cert, key));
+        if let Some(ca) = &self.tls_client_ca {
+            tls = tls.client_ca_root(Certificate::from_pem(std::fs::read(ca)?));
+        }
+        Ok(Some(tls))
+    }
+
+    pub fn token(&self) -> Result<Option<String>, ERROR> {
+        read_token(&self.token, &self.token_file)
+    }
+}
+
+impl ClientOptions {
+    /// Connect to the daemon over tcp or the unix socket, with TLS when asked for.
+    pub async fn channel(&self) -> Result<Channel, ERROR> {
+        let tls = self.tls || self.tls_ca.is_some() || self.tls_cert.is_some();
+        let scheme = if tls { "https" } else { "http" };
+
+        let mut endpoint = match &self.socket {
+            // the uri is not used to connect, only its scheme and authority are sent
+            Some(_) => Endpoint::try_from(format!("{scheme}://localhost"))?,
+            None => Endpoint::try_from(format!(
+                "{scheme}://{addr}:{port}",
+                addr = self.ip.as_deref().unwrap_or("[::1]"),
+                port = self.port
+            ))?,
+        };
+
+        if tls {
+            let mut config = ClientTlsConfig::new();
+            if let Some(ca) = &self.tls_ca {
+                config = config.ca_certificate(Certificate::from_pem(std::fs::read(ca)?));
+            }
+            match (&self.tls_cert, &self.tls_key) {
+                (Some(cert), Some(key)) => {
+                    config = config
+                        .identity(Identity::from_pem(std::fs::read(cert)?, std::fs::read(key)?));
+                }
+                (None, None) => {}
+                _ => return Err("--tls-cert and --tls-key must be given together".into()),
+            }
+            if let Some(domain) = &self.tls_domain {
+                config = config.domain_name(domain);
+            }
+            endpoint = endpoint.tls_config(config)?;
+        }
+
+        let channel = match &self.socket {
+            Some(path) => {
+                let path = path.to_string();
+                endpoint
+                    .connect_with_connector(tower::service_fn(move |_: Uri| {
+                        UnixStream::connect(path.clone())
+                    }))
+                    .await?
+            }
+            None => endpoint.connect().await?,
+        };
+        Ok(channel)
+    }
+
+    pub fn token(&self) -> Result<Option<String>, ERROR> {
+        read_token(&self.token, &self.token_file)
+    }
+
+    pub async fn qc_client(&self) -> Result<QcClient<InterceptedService<Channel, AttachToken>>, ERROR> {
+        let auth = AttachToken::new(self.token()?)?;
+        Ok(QcClient::with_interceptor(self.channel().await?, auth))
+    }
+
+    pub async fn admin_client(
+        &self,
+    ) -> Result<AdminClient<InterceptedService<Channel, AttachToken>>, ERROR> {
+        let auth = AttachToken::new(self.token()?)?;
+        Ok(AdminClient::with_interceptor(self.channel().await?, auth))
+    }
+}
+
+fn read_token(token: &Option<String>, token_file: &Option<String>) -> Result<Option<String>, ERROR> {
+    match (token, token_file) {
+        (Some(token), _) => Ok(Some(token.to_string())),
+        (None, Some(path)) => Ok(Some(std::fs::read_to_string(path)?.trim().to_string())),
+        (None, None) => Ok(None),
+    }
+}
+
+/// Server side, rejects requests without `authorization: Bearer {token}` when a token is set.
+#[derive(Debug, Clone)]
+pub struct RequireToken {
+    expected: Option<String>,
+}
+
+impl RequireToken {
+    pub fn new(token: Option<String>) -> Self {
+        RequireToken {
+            expected: token.map(|v| format!("Bearer {v}")),
+        }
+    }
+}
+
+impl Interceptor for RequireToken {
+    fn call(&mut self, request: Request<()>) -> Result<Request<()>, Status> {
+        let expected = match &self.expected {
+            Some(v) => v,
+            None => return Ok(request),
+        };
+        match request.metadata().get(AUTHORIZATION) {
+            Some(v) if v.as_bytes() == expected.as_bytes() => Ok(request),
+            _ => Err(Status::unauthenticated("Invalid token")),
+        }
+    }
+}
+
+/// Client side, adds `authorization: Bearer {token}` to every request when a token is set.
+#[derive(Debug, Clone)]
+pub struct AttachToken {
+    value: Option<MetadataValue<Ascii>>,
+}
+
+impl AttachToken {
+    pub fn new(token: Option<String>) -> Result<Self, ERROR> {
+        let value = match token {
+            Some(v) => Some(format!("Bearer {v}").parse()?),
+            None => None,
+        };
+        Ok(AttachToken { value })
+    }
+}
+
+impl Interceptor for AttachToken {
+    fn call(&mut self, mut request: Request<()>) -> Result<Request<()>, Status> {
+        if let Some(v) = &self.value {
+            request.metadata_mut().insert(AUTHORIZATION, v.clone());
+        }
+        Ok(request)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn bind_addresses() {
+        assert!(matches!(BindAddr::parse("0.0.0.0:50500").unwrap(), BindAddr::Tcp(_)));
+        assert!(matches!(BindAddr::parse("[::1]:50500").unwrap(), BindAddr::Tcp(_)));
+        assert!(matches!(BindAddr::parse("unix:/tmp/qc.sock").unwrap(), BindAddr::Unix(_)));
+        assert!(BindAddr::parse("localhost").is_err());
+    }
+
+    #[test]
+    fn token_required() {
+        let mut require = RequireToken::new(Some("secret".to_string()));
+        assert!(require.call(Request::new(())).is_err());
+
+        let mut attach = AttachToken::new(Some("wrong".to_string())).unwrap();
+        let request = attach.call(Request::new(())).unwrap();
+        assert!(require.call(request).is_err());
+
+        let mut attach = AttachToken::new(Some("secret".to_string())).unwrap();
+        let request = attach.call(Request::new(())).unwrap();
+        assert!(require.call(request).is_ok());
+
+        let mut open = RequireToken::new(None);
+        assert!(open.call(Request::new(())).is_ok());
+    }
+}